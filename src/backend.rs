@@ -0,0 +1,210 @@
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow, bail};
+use futures::future::BoxFuture;
+use tokio::{process::Command, sync::mpsc};
+use tokio_util::sync::CancellationToken;
+
+use crate::{events::EventMapper, process::spawn_supervised, prompt::assemble_prompt, types::*};
+
+// Largest single command line argument Linux accepts (MAX_ARG_STRLEN), less room for the terminating NUL
+const MAX_ARG_BYTES: usize = 128 * 1024 - 1;
+
+// An agent codecho can run prompts with
+pub trait AgentBackend: fmt::Debug + Send + Sync {
+  // Shown to clients in the server instructions, e.g. `codex-cli 0.39.0`
+  fn describe(&self) -> String;
+
+  // Fails when the request asks for something the backend can't do, such as resuming a session
+  fn prepare(&self, request: &CodexPromptRequest) -> Result<CodexInvocation>;
+
+  // Starts the agent and streams its events to `message_tx`, ending with exactly one `CodexEvent::Exited`.
+  // Cancelling `cancel` stops the agent
+  fn start<'a>(
+    &'a self,
+    invocation: &'a CodexInvocation,
+    message_tx: mpsc::UnboundedSender<CodexEvent>,
+    cancel: CancellationToken,
+  ) -> BoxFuture<'a, Result<()>>;
+
+  // Stops whatever the backend keeps running between prompts; called once no more runs will start
+  fn shutdown(&self) -> BoxFuture<'_, ()> {
+    Box::pin(async {})
+  }
+}
+
+#[derive(Debug)]
+pub struct Backends {
+  default: String,
+  backends: BTreeMap<String, Arc<dyn AgentBackend>>,
+}
+
+impl Backends {
+  pub fn new(config: BackendsConfig, codex: impl AgentBackend + 'static, codex_mcp: impl AgentBackend + 'static, defaults: &CodexConfig) -> Result<Self> {
+    let mut backends = BTreeMap::<String, Arc<dyn AgentBackend>>::new();
+    backends.insert("codex".to_string(), Arc::new(codex));
+    // Only starts its server once a prompt uses it
+    backends.insert("codex-mcp".to_string(), Arc::new(codex_mcp));
+
+    for (name, cli) in config.cli {
+      if cli.command.is_empty() {
+        bail!("Backend `{}` has an empty command", name);
+      }
+
+      tracing::info!("Registered CLI backend `{}` ({})", name, cli.command[0]);
+      backends.insert(name, Arc::new(CliBackend { config: cli, defaults: defaults.clone() }));
+    }
+
+    if !backends.contains_key(&config.default) {
+      bail!("Default backend `{}` is not configured", config.default);
+    }
+
+    Ok(Self { default: config.default, backends })
+  }
+
+  pub fn get(&self, name: Option<&str>) -> Result<Arc<dyn AgentBackend>> {
+    let name = name.unwrap_or(&self.default);
+
+    self.backends.get(name).cloned().ok_or_else(|| anyhow!("Unknown backend `{}`, available: {}", name, self.names().join(", ")))
+  }
+
+  fn names(&self) -> Vec<&str> {
+    self.backends.keys().map(String::as_str).collect()
+  }
+
+  pub async fn shutdown(&self) {
+    futures::future::join_all(self.backends.values().map(|backend| backend.shutdown())).await;
+  }
+
+  pub fn describe(&self) -> String {
+    self
+      .backends
+      .iter()
+      .map(|(name, backend)| {
+        let default = if *name == self.default { ", default" } else { "" };
+        format!("`{}` ({}{})", name, backend.describe(), default)
+      })
+      .collect::<Vec<_>>()
+      .join(", ")
+  }
+}
+
+// Any agent CLI that prints JSON events on stdout, driven by a command template and an event mapping
+#[derive(Debug)]
+struct CliBackend {
+  config: CliBackendConfig,
+  // Timeouts, sandbox mode and model fall back to the codex settings
+  defaults: CodexConfig,
+}
+
+impl CliBackend {
+  fn command(&self, session_id: Option<&str>) -> Result<&[String]> {
+    match (session_id, &self.config.resume_command) {
+      (None, _) => Ok(&self.config.command),
+      (Some(_), Some(resume_command)) if !resume_command.is_empty() => Ok(resume_command),
+      (Some(_), _) => bail!("Backend `{}` has no `resume_command`, so it can't continue a session", self.config.command[0]),
+    }
+  }
+}
+
+impl AgentBackend for CliBackend {
+  fn describe(&self) -> String {
+    self.config.command[0].clone()
+  }
+
+  fn prepare(&self, request: &CodexPromptRequest) -> Result<CodexInvocation> {
+    let command = self.command(request.session_id.as_deref())?;
+    let session_id = request.session_id.clone().unwrap_or_default();
+    let prompt = assemble_prompt(request.clone());
+    let working_dir = request.context.working_dir.clone();
+    let model = self.config.model.clone().unwrap_or_else(|| self.defaults.resolve_model(request).to_string());
+    let sandbox_mode = self.defaults.resolve_sandbox_mode(request);
+    let reasoning_effort = self.defaults.resolve_reasoning_effort(request).to_string();
+
+    let mut args: Vec<String> = command[1..]
+      .iter()
+      .map(|arg| {
+        arg
+          .replace("{session_id}", &session_id)
+          .replace("{working_dir}", &working_dir)
+          .replace("{model}", &model)
+          .replace("{sandbox_mode}", &sandbox_mode.to_string())
+          .replace("{reasoning_effort}", &reasoning_effort)
+          // Last so placeholders inside the prompt are left alone
+          .replace("{prompt}", &prompt)
+      })
+      .collect();
+
+    let prompt_on_stdin = !command.iter().any(|arg| arg.contains("{prompt}"));
+    if !prompt_on_stdin && prompt.len() > MAX_ARG_BYTES {
+      bail!(
+        "The prompt takes {} bytes, more than a command line argument can hold; remove `{{prompt}}` from the `{}` command to pass it on stdin",
+        prompt.len(),
+        command[0]
+      );
+    }
+
+    // The program goes first so `start` knows which of the two commands to run
+    args.insert(0, command[0].clone());
+
+    Ok(CodexInvocation {
+      args,
+      prompt,
+      prompt_on_stdin,
+      working_dir,
+      model,
+      sandbox_mode,
+      timeout_ms: self.defaults.resolve_timeout_ms(request),
+      idle_timeout_ms: self.defaults.resolve_idle_timeout_ms(request),
+    })
+  }
+
+  fn start<'a>(
+    &'a self,
+    invocation: &'a CodexInvocation,
+    message_tx: mpsc::UnboundedSender<CodexEvent>,
+    cancel: CancellationToken,
+  ) -> BoxFuture<'a, Result<()>> {
+    Box::pin(async move {
+      let mut command = Command::new(&invocation.args[0]);
+      command.args(&invocation.args[1..]).envs(&self.config.env).current_dir(&invocation.working_dir);
+
+      let mapper = EventMapper::new(self.config.type_pointer.clone(), self.config.events.clone());
+      let kill_grace = Duration::from_millis(self.defaults.kill_grace_ms);
+
+      spawn_supervised(command, invocation, move |line| mapper.decode(line), kill_grace, message_tx, cancel)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  fn backend(command: &[&str]) -> CliBackend {
+    CliBackend { config: serde_json::from_value(json!({ "command": command })).unwrap(), defaults: CodexConfig::default() }
+  }
+
+  fn request(prompt: &str) -> CodexPromptRequest {
+    CodexPromptRequest { prompt: prompt.to_string(), context: Context { working_dir: "/repo".to_string(), ..Default::default() }, ..Default::default() }
+  }
+
+  #[test]
+  fn prompt_goes_to_stdin_without_a_placeholder() {
+    let invocation = backend(&["agent", "--cd", "{working_dir}"]).prepare(&request(&"x".repeat(MAX_ARG_BYTES + 1))).unwrap();
+
+    assert!(invocation.prompt_on_stdin);
+    assert_eq!(invocation.args, ["agent", "--cd", "/repo"]);
+  }
+
+  #[test]
+  fn prompt_placeholder_is_limited_to_one_argument() {
+    let invocation = backend(&["agent", "{prompt}"]).prepare(&request("hello")).unwrap();
+    assert!(!invocation.prompt_on_stdin);
+    assert_eq!(invocation.args[1], invocation.prompt);
+
+    assert!(backend(&["agent", "{prompt}"]).prepare(&request(&"x".repeat(MAX_ARG_BYTES + 1))).is_err());
+  }
+}
@@ -0,0 +1,109 @@
+// Stands in for `codex mcp-server` when trying out the codex-mcp backend without codex or an API key:
+// `CODEX_BINARY=target/debug/stub_codex_mcp CODECHO_BACKEND=codex-mcp cargo run`
+//
+// Answers `--version` like codex-cli and serves a `codex` tool over stdio that streams a few canned `codex/event`
+// notifications. `STUB_EVENT_DELAY_MS` slows the events down to try out cancellation and timeouts, and `STUB_FAIL=1`
+// makes every call fail.
+
+use std::{
+  collections::HashSet,
+  env,
+  io::{BufRead, Write},
+  sync::{Arc, Mutex},
+  thread,
+  time::Duration,
+};
+
+use serde_json::{Value, json};
+
+fn main() {
+  if env::args().any(|arg| arg == "--version") {
+    println!("codex-cli 0.46.0");
+    return;
+  }
+
+  let delay = Duration::from_millis(env::var("STUB_EVENT_DELAY_MS").ok().and_then(|ms| ms.parse().ok()).unwrap_or(100));
+  let fail = env::var("STUB_FAIL").is_ok_and(|fail| fail == "1");
+
+  let out = Arc::new(Mutex::new(std::io::stdout()));
+  let cancelled = Arc::new(Mutex::new(HashSet::<String>::new()));
+
+  for line in std::io::stdin().lock().lines() {
+    let Ok(line) = line else { break };
+    let Ok(message) = serde_json::from_str::<Value>(&line) else { continue };
+
+    let id = message.get("id").cloned();
+    let method = message.get("method").and_then(Value::as_str).unwrap_or_default();
+
+    match (method, id) {
+      ("initialize", Some(id)) => send(
+        &out,
+        json!({
+          "jsonrpc": "2.0",
+          "id": id,
+          "result": {
+            "protocolVersion": "2025-03-26",
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "stub-codex-mcp", "version": "0.46.0" },
+          },
+        }),
+      ),
+      ("tools/list", Some(id)) => send(&out, json!({ "jsonrpc": "2.0", "id": id, "result": { "tools": [] } })),
+      ("tools/call", Some(id)) => {
+        let prompt = message.pointer("/params/arguments/prompt").and_then(Value::as_str).unwrap_or_default().to_string();
+        let out = out.clone();
+        let cancelled = cancelled.clone();
+
+        thread::spawn(move || run_codex(&out, &cancelled, id, &prompt, delay, fail));
+      }
+      ("notifications/cancelled", None) => {
+        if let Some(request_id) = message.pointer("/params/requestId") {
+          cancelled.lock().unwrap().insert(request_id.to_string());
+        }
+      }
+      (_, Some(id)) => send(&out, json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32601, "message": "Method not found" } })),
+      _ => {}
+    }
+  }
+}
+
+fn run_codex(out: &Mutex<std::io::Stdout>, cancelled: &Mutex<HashSet<String>>, id: Value, prompt: &str, delay: Duration, fail: bool) {
+  let events = [
+    json!({ "type": "session_configured", "session_id": "stub-session", "model": "gpt-5-codex" }),
+    json!({ "type": "task_started", "model_context_window": 272000 }),
+    json!({ "type": "agent_reasoning", "text": "Reading the prompt" }),
+    json!({ "type": "agent_message", "message": format!("Received {} characters of prompt", prompt.len()) }),
+    json!({
+      "type": "token_count",
+      "info": { "total_token_usage": { "input_tokens": 120, "output_tokens": 30, "total_tokens": 150 } },
+    }),
+    json!({ "type": "task_complete", "last_agent_message": null }),
+  ];
+
+  for (seq, msg) in events.into_iter().enumerate() {
+    thread::sleep(delay);
+
+    // Like codex, a cancelled call only reports the aborted turn and gets no response
+    if cancelled.lock().unwrap().contains(&id.to_string()) {
+      let msg = json!({ "type": "turn_aborted", "reason": "interrupted" });
+      send(out, json!({ "jsonrpc": "2.0", "method": "codex/event", "params": { "_meta": { "requestId": id }, "id": seq.to_string(), "msg": msg } }));
+      return;
+    }
+
+    send(out, json!({ "jsonrpc": "2.0", "method": "codex/event", "params": { "_meta": { "requestId": id }, "id": seq.to_string(), "msg": msg } }));
+  }
+
+  let result = if fail {
+    json!({ "content": [{ "type": "text", "text": "stub failure" }], "isError": true })
+  } else {
+    json!({ "content": [{ "type": "text", "text": "done" }] })
+  };
+
+  send(out, json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+}
+
+fn send(out: &Mutex<std::io::Stdout>, message: Value) {
+  let mut out = out.lock().unwrap();
+  let _ = writeln!(out, "{}", message);
+  let _ = out.flush();
+}
@@ -0,0 +1,147 @@
+use std::{fmt, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use tokio::process::Command;
+
+use crate::events::EventSchema;
+
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CodexVersion(pub u32, pub u32, pub u32);
+
+impl fmt::Display for CodexVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}.{}.{}", self.0, self.1, self.2)
+  }
+}
+
+impl CodexVersion {
+  // Accepts `0.39.0` as well as pre-release builds such as `0.46.0-alpha.4`
+  fn parse(s: &str) -> Option<Self> {
+    let s = s.strip_prefix('v').unwrap_or(s);
+    let core = s.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|part| part.parse::<u32>().ok());
+
+    let version = Self(parts.next()??, parts.next()??, parts.next().unwrap_or(Some(0))?);
+    parts.next().is_none().then_some(version)
+  }
+
+  // The first version-looking word of a `codex --version` line such as `codex-cli 0.39.0`
+  fn from_version_line(line: &str) -> Option<Self> {
+    line.split_whitespace().find_map(Self::parse)
+  }
+}
+
+// What the installed codex-cli supports, which decides the `exec` flags and how its output is decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+  // `None` leaves the decoder to detect the schema from the first event
+  pub event_schema: Option<EventSchema>,
+  pub skip_git_repo_check: bool,
+  // Subcommand that serves codex over MCP on stdio
+  pub mcp_server_subcommand: &'static str,
+  // `codex exec resume <session id>`
+  pub exec_resume: bool,
+}
+
+// Newest first; each entry applies from its version up to the next newer entry
+const CAPABILITY_TABLE: &[(CodexVersion, Capabilities)] = &[
+  // `exec --json` switched to the `thread.*`/`item.*` events and `codex mcp` became a config command
+  (
+    CodexVersion(0, 44, 0),
+    Capabilities { event_schema: Some(EventSchema::V2), skip_git_repo_check: true, mcp_server_subcommand: "mcp-server", exec_resume: true },
+  ),
+  // `codex exec resume` continues earlier sessions
+  (CodexVersion(0, 40, 0), Capabilities { event_schema: Some(EventSchema::V1), skip_git_repo_check: true, mcp_server_subcommand: "mcp", exec_resume: true }),
+  (CodexVersion(0, 10, 0), Capabilities { event_schema: Some(EventSchema::V1), skip_git_repo_check: true, mcp_server_subcommand: "mcp", exec_resume: false }),
+  // Releases from before `exec` refused to run outside a git repository
+  (CodexVersion(0, 0, 0), Capabilities { event_schema: Some(EventSchema::V1), skip_git_repo_check: false, mcp_server_subcommand: "mcp", exec_resume: false }),
+];
+
+impl Capabilities {
+  pub fn for_version(version: Option<CodexVersion>) -> Self {
+    match version {
+      Some(version) => CAPABILITY_TABLE.iter().find(|(since, _)| version >= *since).map(|(_, capabilities)| *capabilities).unwrap_or(CAPABILITY_TABLE[0].1),
+      // Assume a release newer than codecho knows about, but don't trust its output format
+      None => Capabilities { event_schema: None, ..CAPABILITY_TABLE[0].1 },
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct CodexInfo {
+  pub version: Option<CodexVersion>,
+  // The raw `codex --version` output, for logs and clients
+  pub version_line: String,
+  pub capabilities: Capabilities,
+}
+
+impl CodexInfo {
+  // Runs `codex --version`; fails when the binary can't be run at all
+  pub async fn detect(binary: &str) -> Result<Self> {
+    let output = tokio::time::timeout(VERSION_TIMEOUT, Command::new(binary).arg("--version").kill_on_drop(true).output())
+      .await
+      .with_context(|| format!("`{} --version` did not finish within {:?}", binary, VERSION_TIMEOUT))?
+      .with_context(|| format!("Failed to run codex binary `{}`; install codex-cli or point CODEX_BINARY at it", binary))?;
+
+    if !output.status.success() {
+      bail!("`{} --version` failed with {}: {}", binary, output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let version_line = String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or_default().trim().to_string();
+    let version = CodexVersion::from_version_line(&version_line);
+
+    match version {
+      Some(version) => tracing::info!("Using codex-cli {} ({})", version, binary),
+      None => tracing::warn!("Could not parse a version from `{} --version` output {:?}, assuming a recent codex-cli", binary, version_line),
+    }
+
+    Ok(Self { version, version_line, capabilities: Capabilities::for_version(version) })
+  }
+}
+
+impl fmt::Display for CodexInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.version {
+      Some(version) => write!(f, "codex-cli {}", version),
+      None if !self.version_line.is_empty() => write!(f, "{}", self.version_line),
+      None => write!(f, "codex-cli (unknown version)"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn version_lines_map_to_capabilities() {
+    let v2 = Capabilities { event_schema: Some(EventSchema::V2), skip_git_repo_check: true, mcp_server_subcommand: "mcp-server", exec_resume: true };
+    let resume = Capabilities { event_schema: Some(EventSchema::V1), skip_git_repo_check: true, mcp_server_subcommand: "mcp", exec_resume: true };
+    let v1 = Capabilities { event_schema: Some(EventSchema::V1), skip_git_repo_check: true, mcp_server_subcommand: "mcp", exec_resume: false };
+    let oldest = Capabilities { skip_git_repo_check: false, ..v1 };
+    // Unparsable output is taken for a newer release whose event schema is detected from the output
+    let unknown = Capabilities { event_schema: None, ..v2 };
+
+    let cases = [
+      ("codex-cli 0.46.0-alpha.4", Some(CodexVersion(0, 46, 0)), v2),
+      ("codex-cli 0.44.0", Some(CodexVersion(0, 44, 0)), v2),
+      ("codex-cli 0.43.9", Some(CodexVersion(0, 43, 9)), resume),
+      ("codex-cli 0.40.0", Some(CodexVersion(0, 40, 0)), resume),
+      ("codex-cli 0.39.0", Some(CodexVersion(0, 39, 0)), v1),
+      ("codex-cli v0.10", Some(CodexVersion(0, 10, 0)), v1),
+      ("codex-cli 0.9.2+build.7", Some(CodexVersion(0, 9, 2)), oldest),
+      ("codex-cli 1.0.0", Some(CodexVersion(1, 0, 0)), v2),
+      ("codex-cli dev", None, unknown),
+      ("codex-cli 0.4x.0", None, unknown),
+      ("codex-cli 0.39.0.1", None, unknown),
+      ("", None, unknown),
+    ];
+
+    for (line, version, capabilities) in cases {
+      assert_eq!(CodexVersion::from_version_line(line), version, "{:?}", line);
+      assert_eq!(Capabilities::for_version(version), capabilities, "{:?}", line);
+    }
+  }
+}
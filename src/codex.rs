@@ -1,214 +1,103 @@
+use crate::backend::AgentBackend;
+use crate::capabilities::CodexInfo;
+use crate::events::EventDecoder;
+use crate::process::spawn_supervised;
+use crate::prompt::assemble_prompt;
 use crate::types::*;
-use anyhow::{Context, Result, anyhow};
-use std::process::Stdio;
-use std::sync::Arc;
+use anyhow::{Result, bail};
+use futures::future::BoxFuture;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug)]
 pub struct CodexClient {
   config: CodexConfig,
+  info: CodexInfo,
 }
 
 impl CodexClient {
-  pub fn new(config: CodexConfig) -> Self {
-    Self { config }
+  pub fn new(config: CodexConfig, info: CodexInfo) -> Self {
+    Self { config, info }
   }
 
-  pub async fn start_prompt_streaming(&self, request: CodexPromptRequest, message_tx: mpsc::UnboundedSender<CodexMessage>) -> Result<()> {
-    let mut args = self.assemble_args(request.clone());
-    let prompt = self.assemble_prompt(request.clone());
-    args.push(prompt);
-
-    let mut child = Command::new(&self.config.binary)
-      .args(&args)
-      .stdin(Stdio::null())
-      .stdout(Stdio::piped())
-      .stderr(Stdio::piped())
-      .spawn()
-      .context("Failed to spawn codex process")?;
-
-    let stdout = child.stdout.take().ok_or_else(|| anyhow!("Failed to capture stdout"))?;
-    let stderr = child.stderr.take().ok_or_else(|| anyhow!("Failed to capture stderr"))?;
-
-    let child = Arc::new(Mutex::new(child));
-    let timeout_ms = request.timeout.unwrap_or(self.config.timeout_ms);
-
-    tokio::spawn({
-      let message_tx = message_tx.clone();
-
-      async move {
-        let reader = BufReader::new(stdout);
-        let mut lines = reader.lines();
-
-        while let Ok(Some(line)) = lines.next_line().await {
-          if let Ok(msg) = serde_json::from_str::<CodexMessage>(&line) {
-            let _ = message_tx.send(msg.clone());
-          }
-        }
-      }
-    });
-
-    // Spawn stderr reader
-    tokio::spawn(async move {
-      let reader = BufReader::new(stderr);
-      let mut lines = reader.lines();
-      let mut error_buffer = String::new();
-
-      while let Ok(Some(line)) = lines.next_line().await {
-        error_buffer.push_str(&line);
-        error_buffer.push('\n');
-      }
-
-      if !error_buffer.is_empty() {
-        tracing::error!("Codex stderr: {}", error_buffer);
-      }
-    });
-
-    // Wait to die
-    tokio::spawn({
-      let child = child.clone();
+  pub async fn start_prompt_streaming(
+    &self,
+    invocation: &CodexInvocation,
+    message_tx: mpsc::UnboundedSender<CodexEvent>,
+    cancel: CancellationToken,
+  ) -> Result<()> {
+    let mut command = Command::new(&self.config.binary);
+    command.args(&invocation.args);
 
-      async move {
-        tokio::time::sleep(Duration::from_millis(timeout_ms)).await;
+    let mut decoder = EventDecoder::new(self.info.capabilities.event_schema);
+    let kill_grace = Duration::from_millis(self.config.kill_grace_ms);
 
-        let mut child = child.lock().await;
-        let _ = child.kill().await;
-      }
-    });
-
-    tokio::spawn({
-      let child = child.clone();
-
-      async move {
-        loop {
-          {
-            match child.lock().await.try_wait() {
-              Ok(Some(exit_status)) => {
-                if !exit_status.success() {
-                  let _ = message_tx.send(CodexMessage {
-                    msg: Some(MessageContent {
-                      msg_type: MessageType::Error,
-                      text: Some(format!("Codex process exited with status: {}", exit_status)),
-                      ..Default::default()
-                    }),
-                    ..Default::default()
-                  });
-                }
-
-                break;
-              }
-              Ok(None) => {
-                tokio::time::sleep(Duration::from_millis(100)).await;
-              }
-              Err(e) => {
-                let _ = message_tx.send(CodexMessage {
-                  msg: Some(MessageContent { msg_type: MessageType::Error, text: Some(e.to_string()), ..Default::default() }),
-                  ..Default::default()
-                });
-              }
-            }
-          }
-        }
-      }
-    });
-
-    Ok(())
+    spawn_supervised(command, invocation, move |line| decoder.decode(line), kill_grace, message_tx, cancel)
   }
 
-  fn assemble_args(&self, request: CodexPromptRequest) -> Vec<String> {
+  fn assemble_args(&self, request: CodexPromptRequest) -> Result<Vec<String>> {
     let mut args = vec!["exec".to_string(), "--json".to_string()];
 
-    let sandbox_mode = if let Some(sandbox_mode) = request.sandbox_mode {
-      sandbox_mode.to_string()
-    } else if let Some(sandbox_mode) = self.config.sandbox_mode.clone() {
-      sandbox_mode.to_string()
-    } else {
-      SandboxMode::ReadOnly.to_string()
-    };
-
     args.push("--sandbox".to_string());
-    args.push(sandbox_mode);
-
-    let model = if let Some(model) = request.model {
-      model.to_string()
-    } else if let Some(model) = self.config.model.clone() {
-      model.to_string()
-    } else {
-      Model::Gpt5Codex.to_string()
-    };
+    args.push(self.config.resolve_sandbox_mode(&request).to_string());
 
     args.push("--model".to_string());
-    args.push(model);
-
-    let reasoning_effort = if let Some(reasoning_effort) = request.reasoning_effort {
-      reasoning_effort.to_string()
-    } else if let Some(reasoning_effort) = self.config.reasoning_effort.clone() {
-      reasoning_effort.to_string()
-    } else {
-      ReasoningEffort::Medium.to_string()
-    };
+    args.push(self.config.resolve_model(&request).to_string());
 
     args.push("--config".to_string());
-    args.push(format!("model_reasoning_effort={}", reasoning_effort));
+    args.push(format!("model_reasoning_effort={}", self.config.resolve_reasoning_effort(&request)));
 
     let working_dir = request.context.working_dir.clone();
     args.push("--cd".to_string());
     args.push(working_dir.clone());
 
-    args.push("-skip-git-repo-check".to_string());
-
-    args
-  }
-
-  fn assemble_prompt(&self, request: CodexPromptRequest) -> String {
-    use std::fmt::Write;
-
-    let mut prompt = String::new();
-    let context = request.context;
-
-    writeln!(&mut prompt, "# Context").unwrap();
-    writeln!(&mut prompt).unwrap();
-
-    writeln!(&mut prompt, "Working directory: {}", context.working_dir).unwrap();
-    writeln!(&mut prompt).unwrap();
-
-    if let Some(files) = &context.files {
-      writeln!(&mut prompt, "## Files").unwrap();
-      writeln!(&mut prompt).unwrap();
-
-      for file in files {
-        write!(&mut prompt, "{}", file.path).unwrap();
+    if self.info.capabilities.skip_git_repo_check {
+      args.push("--skip-git-repo-check".to_string());
+    }
 
-        if let Some(content) = &file.content {
-          writeln!(&mut prompt, ":").unwrap();
-          writeln!(&mut prompt, "{}", content).unwrap();
-          writeln!(&mut prompt).unwrap();
-        } else {
-          writeln!(&mut prompt).unwrap();
-        }
+    if let Some(session_id) = request.session_id {
+      if !self.info.capabilities.exec_resume {
+        bail!("{} can't resume sessions, codex-cli 0.40.0 or later is needed", self.info);
       }
 
-      writeln!(&mut prompt).unwrap();
+      args.push("resume".to_string());
+      args.push(session_id);
     }
 
-    if let Some(variables) = &context.variables {
-      writeln!(&mut prompt, "## Variables").unwrap();
-      writeln!(&mut prompt).unwrap();
+    // The prompt, after the session to resume, is read from stdin: with context files it easily outgrows the size of
+    // a single argument
+    args.push("-".to_string());
 
-      for (key, value) in variables {
-        writeln!(&mut prompt, "{}: {}", key, value).unwrap();
-      }
+    Ok(args)
+  }
+}
 
-      writeln!(&mut prompt).unwrap();
-    }
+impl AgentBackend for CodexClient {
+  fn describe(&self) -> String {
+    self.info.to_string()
+  }
 
-    writeln!(&mut prompt, "## User Prompt").unwrap();
-    writeln!(&mut prompt).unwrap();
-    prompt.push_str(&request.prompt);
+  fn prepare(&self, request: &CodexPromptRequest) -> Result<CodexInvocation> {
+    Ok(CodexInvocation {
+      args: self.assemble_args(request.clone())?,
+      prompt: assemble_prompt(request.clone()),
+      prompt_on_stdin: true,
+      working_dir: request.context.working_dir.clone(),
+      model: self.config.resolve_model(request).to_string(),
+      sandbox_mode: self.config.resolve_sandbox_mode(request),
+      timeout_ms: self.config.resolve_timeout_ms(request),
+      idle_timeout_ms: self.config.resolve_idle_timeout_ms(request),
+    })
+  }
 
-    prompt
+  fn start<'a>(
+    &'a self,
+    invocation: &'a CodexInvocation,
+    message_tx: mpsc::UnboundedSender<CodexEvent>,
+    cancel: CancellationToken,
+  ) -> BoxFuture<'a, Result<()>> {
+    Box::pin(self.start_prompt_streaming(invocation, message_tx, cancel))
   }
 }
@@ -0,0 +1,414 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  process::Stdio,
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+  },
+  time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use futures::future::BoxFuture;
+use rmcp::{
+  ServiceExt,
+  model::{CallToolRequestParam, CancelledNotificationParam, ClientCapabilities, ClientInfo, ClientRequest, Implementation, JsonObject, Request, ServerResult},
+  service::{PeerRequestOptions, RoleClient, RunningService},
+};
+use serde_json::{Value, json};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream},
+  process::{Child, ChildStdout, Command},
+  sync::{Mutex as AsyncMutex, mpsc},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  backend::AgentBackend,
+  capabilities::CodexInfo,
+  process::{InFlightCalls, terminate_process_group},
+  prompt::assemble_prompt,
+  types::*,
+};
+
+// Room for the MCP messages codecho hasn't read yet; the codex events themselves never pass through it
+const TRANSPORT_BUFFER_BYTES: usize = 1024 * 1024;
+
+// How many finished tool calls are remembered so their late events are dropped instead of buffered for good
+const FINISHED_ROUTES: usize = 256;
+
+// Runs prompts as `codex` tool calls on one long-lived `codex mcp-server` process instead of an `exec` process per
+// prompt, saving node startup on every run
+#[derive(Debug)]
+pub struct CodexMcpBackend {
+  config: CodexConfig,
+  info: CodexInfo,
+  // Started on first use and restarted if the server goes away
+  server: AsyncMutex<Option<Arc<McpServer>>>,
+  // Conversations the running server can continue, by session id; they live only as long as the server process
+  conversations: Arc<Mutex<HashMap<String, Conversation>>>,
+}
+
+// What a conversation was started with. `codex-reply` keeps these whatever the reply's request says, so the run is
+// locked, limited and reported by them
+#[derive(Debug, Clone)]
+struct Conversation {
+  model: String,
+  sandbox_mode: SandboxMode,
+  working_dir: String,
+}
+
+#[derive(Debug)]
+struct McpServer {
+  client: RunningService<RoleClient, ClientInfo>,
+  routes: Arc<EventRoutes>,
+  child: AsyncMutex<Child>,
+  // Set once the server is being stopped, so no new run is started on it
+  terminated: AtomicBool,
+}
+
+// Delivers the `codex/event` notifications to the run whose tool call they belong to
+#[derive(Debug, Default)]
+struct EventRoutes {
+  routes: Mutex<RouteTable>,
+}
+
+#[derive(Debug, Default)]
+struct RouteTable {
+  routes: HashMap<String, Route>,
+  // Finished request ids, oldest first
+  finished: VecDeque<String>,
+}
+
+#[derive(Debug)]
+enum Route {
+  Attached(mpsc::UnboundedSender<CodexMessage>),
+  // Events that arrived before the run learned its request id
+  Buffered(Vec<CodexMessage>),
+  Finished,
+}
+
+impl CodexMcpBackend {
+  pub fn new(config: CodexConfig, info: CodexInfo) -> Self {
+    Self { config, info, server: AsyncMutex::new(None), conversations: Arc::default() }
+  }
+
+  async fn server(&self) -> Result<Arc<McpServer>> {
+    let mut server = self.server.lock().await;
+
+    if let Some(running) = server.as_ref().filter(|running| running.is_running()) {
+      return Ok(running.clone());
+    }
+
+    if server.is_some() {
+      tracing::warn!("Codex MCP server went away, restarting it");
+    }
+
+    let running = Arc::new(self.spawn_server().await?);
+    self.conversations.lock().unwrap().clear();
+    *server = Some(running.clone());
+    Ok(running)
+  }
+
+  async fn spawn_server(&self) -> Result<McpServer> {
+    let subcommand = self.info.capabilities.mcp_server_subcommand;
+
+    let mut command = Command::new(&self.config.binary);
+    command.arg(subcommand).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let mut child = command.spawn().with_context(|| format!("Failed to spawn `{} {}`", self.config.binary, subcommand))?;
+
+    let stdin = child.stdin.take().ok_or_else(|| anyhow!("Failed to capture stdin"))?;
+    let stdout = child.stdout.take().ok_or_else(|| anyhow!("Failed to capture stdout"))?;
+    let stderr = child.stderr.take().ok_or_else(|| anyhow!("Failed to capture stderr"))?;
+
+    // rmcp's stdio codec drops notifications outside the MCP spec, so the codex events are taken out of the stream
+    // before the rest reaches the client
+    let routes = Arc::new(EventRoutes::default());
+    let (transport, messages) = tokio::io::duplex(TRANSPORT_BUFFER_BYTES);
+    tokio::spawn(route_stdout(stdout, messages, routes.clone()));
+
+    tokio::spawn(async move {
+      let mut lines = BufReader::new(stderr).lines();
+      while let Ok(Some(line)) = lines.next_line().await {
+        tracing::warn!("Codex MCP server stderr: {}", line);
+      }
+    });
+
+    let client_info = ClientInfo {
+      protocol_version: Default::default(),
+      capabilities: ClientCapabilities::default(),
+      client_info: Implementation { name: "codecho".to_string(), title: None, version: env!("CARGO_PKG_VERSION").to_string(), website_url: None, icons: None },
+    };
+
+    let client = client_info.serve((transport, stdin)).await.context("Failed to initialize the codex MCP server")?;
+    tracing::info!("Started codex MCP server (`{} {}`)", self.config.binary, subcommand);
+
+    Ok(McpServer { client, routes, child: AsyncMutex::new(child), terminated: AtomicBool::new(false) })
+  }
+
+  async fn run(&self, invocation: &CodexInvocation, message_tx: mpsc::UnboundedSender<CodexEvent>, cancel: CancellationToken) -> Result<()> {
+    let server = self.server().await?;
+
+    let tool = invocation.args[0].clone();
+    let mut arguments: JsonObject = serde_json::from_str(&invocation.args[1]).context("Invalid codex tool arguments")?;
+    arguments.insert("prompt".to_string(), invocation.prompt.clone().into());
+
+    let call = ClientRequest::CallToolRequest(Request::new(CallToolRequestParam { name: tool.into(), arguments: Some(arguments) }));
+    let handle = server.client.peer().send_cancellable_request(call, PeerRequestOptions::no_options()).await.context("Failed to send the codex tool call")?;
+
+    let request_id = handle.id.clone();
+    let mut events = server.routes.attach(&request_id.to_string());
+
+    let timeout_ms = invocation.timeout_ms;
+    let idle_timeout_ms = invocation.idle_timeout_ms;
+    let kill_grace = Duration::from_millis(self.config.kill_grace_ms);
+    let conversations = self.conversations.clone();
+    let conversation =
+      Conversation { model: invocation.model.clone(), sandbox_mode: invocation.sandbox_mode.clone(), working_dir: invocation.working_dir.clone() };
+
+    tokio::spawn(async move {
+      let deadline = tokio::time::sleep(Duration::from_millis(timeout_ms));
+      tokio::pin!(deadline);
+      let mut response = handle.rx;
+      let mut in_flight = InFlightCalls::default();
+
+      let (stop, call_error) = loop {
+        let busy = !in_flight.is_empty();
+        let idle = async move {
+          match idle_timeout_ms {
+            0 => std::future::pending().await,
+            _ if busy => std::future::pending().await,
+            ms => tokio::time::sleep(Duration::from_millis(ms)).await,
+          }
+        };
+
+        tokio::select! {
+          Some(message) = events.recv() => {
+            in_flight.observe(&message);
+            if let Some(EventMsg::SessionConfigured { session_id, .. }) = &message.msg {
+              conversations.lock().unwrap().insert(session_id.clone(), conversation.clone());
+            }
+            let _ = message_tx.send(CodexEvent::Message(Box::new(message)));
+          }
+          response = &mut response => break (None, call_error(response)),
+          _ = &mut deadline => break (Some(StopCause::Timeout), None),
+          _ = idle => break (Some(StopCause::Idle), None),
+          _ = cancel.cancelled() => break (Some(StopCause::Cancelled), None),
+        }
+      };
+
+      if let Some(stop) = stop {
+        tracing::info!("Stopping codex tool call {} ({:?})", request_id, stop);
+        let reason = Some(format!("{:?}", stop));
+        let _ = server.client.peer().notify_cancelled(CancelledNotificationParam { request_id: request_id.clone(), reason }).await;
+
+        // Exiting frees the run's slot and working directory lock, so codex must really have stopped by then. rmcp
+        // answers the cancelled call itself, so only codex's own event for the aborted turn shows that; without it the
+        // whole server goes, with whatever else runs on it
+        let stopped = async {
+          while let Some(message) = events.recv().await {
+            let done = matches!(message.msg, Some(EventMsg::TurnAborted { .. } | EventMsg::TaskComplete { .. }));
+            let _ = message_tx.send(CodexEvent::Message(Box::new(message)));
+            if done {
+              return;
+            }
+          }
+        };
+
+        if tokio::time::timeout(kill_grace, stopped).await.is_err() {
+          tracing::warn!("Codex tool call {} did not stop within {:?} of being cancelled, stopping the codex MCP server", request_id, kill_grace);
+          server.terminate(kill_grace).await;
+        }
+      }
+
+      // Events that were already routed still belong to the transcript
+      server.routes.detach(&request_id.to_string());
+      while let Ok(message) = events.try_recv() {
+        let _ = message_tx.send(CodexEvent::Message(Box::new(message)));
+      }
+
+      if let Some(message) = &call_error {
+        let _ = message_tx.send(CodexEvent::Message(Box::new(CodexMessage { msg: Some(EventMsg::Error { message: message.clone() }), ..Default::default() })));
+      }
+
+      let exit_code = (stop.is_none() && call_error.is_none()).then_some(0);
+      let _ = message_tx.send(CodexEvent::Exited(RunExit { exit_code, stop, call_error, ..Default::default() }));
+    });
+
+    Ok(())
+  }
+}
+
+impl AgentBackend for CodexMcpBackend {
+  fn describe(&self) -> String {
+    format!("{} via `codex {}`", self.info, self.info.capabilities.mcp_server_subcommand)
+  }
+
+  fn prepare(&self, request: &CodexPromptRequest) -> Result<CodexInvocation> {
+    // The tool to call and its arguments apart from the prompt; a continued conversation keeps its original settings
+    let (tool, arguments, Conversation { model, sandbox_mode, working_dir }) = match &request.session_id {
+      Some(session_id) => {
+        let conversation = self
+          .conversations
+          .lock()
+          .unwrap()
+          .get(session_id)
+          .cloned()
+          .ok_or_else(|| anyhow!("Session {} was not started on the running codex MCP server, so it can't be continued", session_id))?;
+
+        if let Some(sandbox_mode) = request.sandbox_mode.as_ref().filter(|sandbox_mode| sandbox_mode.to_string() != conversation.sandbox_mode.to_string()) {
+          bail!("Session {} runs with sandbox mode {}, a continued conversation can't switch to {}", session_id, conversation.sandbox_mode, sandbox_mode);
+        }
+
+        ("codex-reply", json!({ "conversationId": session_id }), conversation)
+      }
+      // Nobody is around to approve commands, so codex must never ask
+      None => {
+        let conversation = Conversation {
+          model: self.config.resolve_model(request).to_string(),
+          sandbox_mode: self.config.resolve_sandbox_mode(request),
+          working_dir: request.context.working_dir.clone(),
+        };
+
+        let arguments = json!({
+          "model": conversation.model,
+          "sandbox": conversation.sandbox_mode.to_string(),
+          "cwd": conversation.working_dir,
+          "approval-policy": "never",
+          "config": { "model_reasoning_effort": self.config.resolve_reasoning_effort(request).to_string() },
+        });
+
+        ("codex", arguments, conversation)
+      }
+    };
+
+    Ok(CodexInvocation {
+      args: vec![tool.to_string(), arguments.to_string()],
+      prompt: assemble_prompt(request.clone()),
+      prompt_on_stdin: false,
+      working_dir,
+      model,
+      sandbox_mode,
+      timeout_ms: self.config.resolve_timeout_ms(request),
+      idle_timeout_ms: self.config.resolve_idle_timeout_ms(request),
+    })
+  }
+
+  fn start<'a>(
+    &'a self,
+    invocation: &'a CodexInvocation,
+    message_tx: mpsc::UnboundedSender<CodexEvent>,
+    cancel: CancellationToken,
+  ) -> BoxFuture<'a, Result<()>> {
+    Box::pin(self.run(invocation, message_tx, cancel))
+  }
+
+  // Dropping the server would only kill its leader, leaving any commands codex started running
+  fn shutdown(&self) -> BoxFuture<'_, ()> {
+    Box::pin(async move {
+      if let Some(server) = self.server.lock().await.take() {
+        tracing::info!("Stopping the codex MCP server");
+        server.terminate(Duration::from_millis(self.config.kill_grace_ms)).await;
+      }
+    })
+  }
+}
+
+impl McpServer {
+  fn is_running(&self) -> bool {
+    !self.terminated.load(Ordering::SeqCst) && !self.client.peer().is_transport_closed()
+  }
+
+  // Stops the server and every command codex launched from it
+  async fn terminate(&self, grace: Duration) {
+    self.terminated.store(true, Ordering::SeqCst);
+    terminate_process_group(&mut *self.child.lock().await, grace).await;
+  }
+}
+
+impl EventRoutes {
+  fn attach(&self, request_id: &str) -> mpsc::UnboundedReceiver<CodexMessage> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let routes = &mut self.routes.lock().unwrap().routes;
+
+    if let Some(Route::Buffered(buffered)) = routes.remove(request_id) {
+      for message in buffered {
+        let _ = tx.send(message);
+      }
+    }
+
+    routes.insert(request_id.to_string(), Route::Attached(tx));
+    rx
+  }
+
+  fn detach(&self, request_id: &str) {
+    let table = &mut *self.routes.lock().unwrap();
+    table.routes.insert(request_id.to_string(), Route::Finished);
+    table.finished.push_back(request_id.to_string());
+
+    while table.finished.len() > FINISHED_ROUTES {
+      let oldest = table.finished.pop_front().unwrap();
+      table.routes.remove(&oldest);
+    }
+  }
+
+  fn deliver(&self, request_id: String, message: CodexMessage) {
+    match self.routes.lock().unwrap().routes.entry(request_id).or_insert_with(|| Route::Buffered(Vec::new())) {
+      Route::Attached(tx) => {
+        let _ = tx.send(message);
+      }
+      Route::Buffered(buffered) => buffered.push(message),
+      // The run already has its transcript
+      Route::Finished => {}
+    }
+  }
+}
+
+// Splits the server's stdout into codex events, routed by the request id codex tags them with, and everything else,
+// which is passed on to the MCP client
+async fn route_stdout(stdout: ChildStdout, mut messages: DuplexStream, routes: Arc<EventRoutes>) {
+  let mut lines = BufReader::new(stdout).lines();
+
+  while let Ok(Some(line)) = lines.next_line().await {
+    if let Some((request_id, message)) = codex_event(&line) {
+      routes.deliver(request_id, message);
+      continue;
+    }
+
+    if messages.write_all(line.as_bytes()).await.is_err() || messages.write_all(b"\n").await.is_err() {
+      break;
+    }
+  }
+}
+
+fn codex_event(line: &str) -> Option<(String, CodexMessage)> {
+  let mut value = serde_json::from_str::<Value>(line).ok()?;
+  if value.get("method").and_then(Value::as_str) != Some("codex/event") {
+    return None;
+  }
+
+  let params = value.get_mut("params")?.take();
+  let request_id = match params.pointer("/_meta/requestId")? {
+    Value::String(id) => id.clone(),
+    id => id.to_string(),
+  };
+
+  Some((request_id, serde_json::from_value(params).ok()?))
+}
+
+fn call_error(response: Result<Result<ServerResult, rmcp::ServiceError>, tokio::sync::oneshot::error::RecvError>) -> Option<String> {
+  match response {
+    Ok(Ok(ServerResult::CallToolResult(result))) if result.is_error == Some(true) => {
+      let text = result.content.iter().filter_map(|content| content.as_text().map(|text| text.text.clone())).collect::<Vec<_>>().join("\n");
+      Some(if text.is_empty() { "codex tool call returned an error".to_string() } else { text })
+    }
+    Ok(Ok(ServerResult::CallToolResult(_))) => None,
+    Ok(Ok(other)) => Some(format!("Unexpected response to the codex tool call: {:?}", other)),
+    Ok(Err(e)) => Some(e.to_string()),
+    Err(_) => Some("The codex MCP server went away".to_string()),
+  }
+}
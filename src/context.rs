@@ -0,0 +1,772 @@
+use std::{
+  cmp::Reverse,
+  collections::HashSet,
+  fs::File,
+  io::Read,
+  ops::Range,
+  path::{Path, PathBuf},
+};
+
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+
+use crate::types::{Context, ContextConfig, ContextFile, ContextFileNote};
+
+// Bytes inspected for NUL bytes, the same heuristic git uses to call a file binary
+const BINARY_SNIFF_BYTES: usize = 8000;
+
+// Files with a line range or symbol are searched up to this size, beyond the per-file limit
+const MAX_SELECTION_SOURCE_BYTES: u64 = 10 * 1024 * 1024;
+
+// Words that start a definition of the name that follows them in Rust, Python, JavaScript/TypeScript, Go and the like.
+// Local variables (`let`, `var`) are left out so they can't stand in for the function of the same name
+const DEFINITION_KEYWORDS: &[&str] =
+  &["fn", "struct", "enum", "union", "trait", "mod", "type", "macro_rules", "const", "static", "class", "def", "function", "interface", "func"];
+
+const BYTES_PER_TOKEN: u64 = 4;
+
+// Files that would keep fewer tokens than this are left out rather than truncated
+const MIN_TRUNCATED_TOKENS: u64 = 256;
+
+// Room for the line that marks a truncated file in the prompt
+const MARKER_TOKENS: u64 = 16;
+
+// Share of the model's context window a prompt may fill when the request sets no budget; the rest is left for codex's
+// own instructions, the tool output it reads and its reply
+const CONTEXT_WINDOW_BUDGET_PERCENT: u64 = 50;
+
+// Gitignore-style file that keeps files out of glob matches without touching the repository's .gitignore
+const IGNORE_FILE: &str = ".codechoignore";
+
+// Reads the context files a request names only by path, so clients don't have to send file contents themselves
+#[derive(Debug)]
+pub struct ContextLoader {
+  config: ContextConfig,
+}
+
+impl ContextLoader {
+  pub fn new(config: ContextConfig) -> Self {
+    Self { config }
+  }
+
+  // Fills in the content of every file that has none, cuts out the selected lines, and reports the files that were truncated or skipped
+  pub async fn load(&self, context: &mut Context) -> Vec<ContextFileNote> {
+    if context.files.as_ref().is_none_or(|files| files.iter().all(|file| file.content.is_some() && !has_selection(file))) {
+      return Vec::new();
+    }
+
+    let config = self.config.clone();
+    let mut loaded = context.clone();
+
+    match tokio::task::spawn_blocking(move || (load_files(&mut loaded, &config), loaded)).await {
+      Ok((notes, loaded)) => {
+        *context = loaded;
+        notes
+      }
+      Err(e) => {
+        tracing::error!("Failed to load context files: {}", e);
+        Vec::new()
+      }
+    }
+  }
+}
+
+fn load_files(context: &mut Context, config: &ContextConfig) -> Vec<ContextFileNote> {
+  let mut notes = Vec::new();
+  let Some(files) = &mut context.files else { return notes };
+
+  let root = Path::new(&context.working_dir).canonicalize();
+
+  if let Ok(root) = &root
+    && files.iter().any(|file| is_pattern(root, file))
+  {
+    *files = expand_patterns(root, std::mem::take(files), config.max_glob_files, &mut notes);
+  }
+
+  let mut remaining = config.max_total_bytes;
+
+  for file in files.iter_mut() {
+    match file.content.take() {
+      // Content the client sent is used as is, apart from its selection
+      Some(content) if has_selection(file) => match select(file, &content) {
+        Ok(selected) => file.content = Some(selected),
+        Err(reason) => notes.push(skipped(file, reason)),
+      },
+      Some(content) => file.content = Some(content),
+      None => {
+        let root = match &root {
+          Ok(root) => root,
+          Err(e) => {
+            notes.push(skipped(file, format!("working directory {} is not readable: {}", context.working_dir, e)));
+            continue;
+          }
+        };
+
+        if remaining == 0 {
+          notes.push(skipped(file, "the total context size limit was reached".to_string()));
+          continue;
+        }
+
+        let limit = remaining.min(config.max_file_bytes);
+        match load_file(root, file, limit) {
+          Ok(note) => {
+            remaining = remaining.saturating_sub(file.content.as_ref().map_or(0, |content| content.len() as u64));
+            notes.extend(note);
+          }
+          Err(reason) => notes.push(skipped(file, reason)),
+        }
+      }
+    }
+  }
+
+  notes
+}
+
+fn skipped(file: &ContextFile, reason: String) -> ContextFileNote {
+  tracing::info!("Skipped context file {}: {}", file.path, reason);
+  ContextFileNote::Skipped { path: file.path.clone(), reason }
+}
+
+// Reads a file's content, or its selected lines, cut at `limit` bytes. Reports when it had to be cut
+fn load_file(root: &Path, file: &mut ContextFile, limit: u64) -> Result<Option<ContextFileNote>, String> {
+  // A selection usually picks a small part of a large file, so it's looked for in more than `limit` bytes
+  let read = read_file(root, &file.path, if has_selection(file) { limit.max(MAX_SELECTION_SOURCE_BYTES) } else { limit })?;
+
+  let (content, size) = match has_selection(file) {
+    true => {
+      let selected = select(file, &read.content)?;
+      let size = selected.len() as u64;
+      (selected, size)
+    }
+    false => (read.content, read.size),
+  };
+
+  file.content = Some(content);
+  if size <= limit {
+    return Ok(None);
+  }
+
+  file.truncated_from = Some(size);
+  Ok(Some(truncate(file, limit)))
+}
+
+// Cuts a file's content to at most `limit` bytes at a character boundary; the prompt marks where it was cut
+fn truncate(file: &mut ContextFile, limit: u64) -> ContextFileNote {
+  let content = file.content.get_or_insert_default();
+  let size = *file.truncated_from.get_or_insert(content.len() as u64);
+
+  let mut cut = (limit as usize).min(content.len());
+  while !content.is_char_boundary(cut) {
+    cut -= 1;
+  }
+  content.truncate(cut);
+
+  // Line numbers only go as far as the lines that made it in
+  if let Some(start) = file.start_line {
+    file.end_line = Some(start + content.lines().count().saturating_sub(1));
+  }
+
+  tracing::info!("Truncated context file {} to {} of {} bytes", file.path, cut, size);
+  ContextFileNote::Truncated { path: file.path.clone(), included_bytes: cut as u64, file_bytes: size }
+}
+
+// The context budget of a prompt for a model with this context window
+pub fn window_budget(context_window: u64) -> u64 {
+  context_window * CONTEXT_WINDOW_BUDGET_PERCENT / 100
+}
+
+// Rough token count: about four bytes per token for English text and code
+pub fn estimate_tokens(text: &str) -> u64 {
+  (text.len() as u64).div_ceil(BYTES_PER_TOKEN)
+}
+
+// Shortens or leaves out context files until the prompt assembled from `context` fits in `budget` tokens. Files listed
+// explicitly keep their content before glob matches, then higher priorities and smaller files go first. Returns
+// whether any file changed, in which case the prompt needs assembling again
+pub fn pack(context: &mut Context, prompt: &str, budget: u64, notes: &mut Vec<ContextFileNote>) -> bool {
+  let needed = estimate_tokens(prompt);
+  let Some(files) = context.files.as_mut().filter(|_| needed > budget) else { return false };
+
+  let file_tokens: u64 = files.iter().filter_map(|file| file.content.as_deref()).map(estimate_tokens).sum();
+  let mut available = budget.saturating_sub(needed.saturating_sub(file_tokens));
+  tracing::info!("Prompt takes about {} tokens, over its context budget of {}; fitting context files into {}", needed, budget, available);
+
+  let mut order: Vec<usize> = (0..files.len()).filter(|&i| files[i].content.is_some()).collect();
+  order.sort_by_key(|&i| (files[i].matched, Reverse(files[i].priority.unwrap_or_default()), files[i].content.as_ref().map_or(0, String::len)));
+
+  let mut changed = false;
+  for i in order {
+    let file = &mut files[i];
+    let tokens = estimate_tokens(file.content.as_deref().unwrap_or_default());
+    if tokens <= available {
+      available -= tokens;
+      continue;
+    }
+
+    // A budget truncation replaces the note of an earlier cut to the byte limits
+    notes.retain(|note| !matches!(note, ContextFileNote::Truncated { path, .. } if *path == file.path));
+    changed = true;
+
+    if available >= MIN_TRUNCATED_TOKENS {
+      notes.push(truncate(file, (available - MARKER_TOKENS) * BYTES_PER_TOKEN));
+      available = 0;
+    } else {
+      tracing::info!("Left out context file {} ({} tokens) to fit the context budget", file.path, tokens);
+      file.content = None;
+      file.truncated_from = None;
+      notes.push(ContextFileNote::Dropped { path: file.path.clone(), estimated_tokens: tokens });
+    }
+  }
+
+  changed
+}
+
+fn has_selection(file: &ContextFile) -> bool {
+  file.symbol.is_some() || file.start_line.is_some() || file.end_line.is_some()
+}
+
+// Cuts the lines that `symbol`, or else `start_line`/`end_line`, select out of `content` and records the 1-based
+// range they cover in the file
+fn select(file: &mut ContextFile, content: &str) -> Result<String, String> {
+  let lines: Vec<&str> = content.split_inclusive('\n').collect();
+
+  let (start, end) = match &file.symbol {
+    Some(symbol) => {
+      let names: Vec<&str> = symbol.split("::").flat_map(|part| part.split('.')).filter(|name| !name.is_empty()).collect();
+      let (start, end) = find_symbol(&lines, 0..lines.len(), &names).ok_or_else(|| format!("symbol `{}` not found", symbol))?;
+      (start + 1, end + 1)
+    }
+    None => line_range(lines.len(), file.start_line, file.end_line)?,
+  };
+
+  file.start_line = Some(start);
+  file.end_line = Some(end);
+
+  Ok(lines[start - 1..end].concat())
+}
+
+fn line_range(line_count: usize, start_line: Option<usize>, end_line: Option<usize>) -> Result<(usize, usize), String> {
+  let start = start_line.unwrap_or(1).max(1);
+  let end = end_line.unwrap_or(line_count).min(line_count);
+
+  if start > line_count {
+    return Err(format!("start_line {} is past the end of the file ({} lines)", start, line_count));
+  }
+
+  if start > end {
+    return Err(format!("start_line {} is after end_line {}", start, end));
+  }
+
+  Ok((start, end))
+}
+
+// Finds the definition of `names[0]` within `window` and returns its 0-based line range, including the comments and
+// attributes right above it. Further names are looked up inside the definition, so `Type::method` finds `method` in
+// whichever `impl Type` or `class Type` defines it. The least indented definition wins, so one nested in a function
+// body doesn't shadow the top-level one
+fn find_symbol(lines: &[&str], window: Range<usize>, names: &[&str]) -> Option<(usize, usize)> {
+  let (name, rest) = names.split_first()?;
+
+  let mut candidates: Vec<usize> = window.clone().filter(|&line| defines(lines[line], name)).collect();
+  candidates.sort_by_key(|&line| lines[line].len() - lines[line].trim_start().len());
+
+  for line in candidates {
+    let end = block_end(lines, line).min(window.end - 1);
+
+    if rest.is_empty() {
+      return Some((preamble_start(lines, line, window.start), end));
+    }
+
+    if let Some(found) = find_symbol(lines, line + 1..end + 1, rest) {
+      return Some(found);
+    }
+  }
+
+  None
+}
+
+// Whether `line` defines `name`, going by the definition keywords of common languages
+fn defines(line: &str, name: &str) -> bool {
+  let trimmed = line.trim_start();
+  if ["//", "/*", "*", "#"].iter().any(|comment| trimmed.starts_with(comment)) {
+    return false;
+  }
+
+  let words: Vec<&str> = trimmed.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$')).filter(|word| !word.is_empty()).collect();
+
+  words.iter().enumerate().any(|(i, word)| match *word {
+    // `impl<T> Trait for Type<T>` belongs to both `Trait` and `Type`
+    "impl" => words[i + 1..].contains(&name),
+    keyword if DEFINITION_KEYWORDS.contains(&keyword) => words.get(i + 1) == Some(&name),
+    _ => false,
+  })
+}
+
+// Last line of the definition starting at `line`: its closing brace, the `;` ending a declaration without a body, or
+// the end of its indented block in languages like Python
+fn block_end(lines: &[&str], line: usize) -> usize {
+  let mut braces = 0usize;
+  // Parentheses and brackets, so `fn f(x: [u8; 4])` doesn't end at its `;`
+  let mut nesting = 0usize;
+  let mut opened = false;
+
+  for (i, text) in lines.iter().enumerate().skip(line) {
+    if !opened && nesting == 0 && i > line && text.trim().is_empty() {
+      return i - 1;
+    }
+
+    for c in text.chars() {
+      match c {
+        '{' => {
+          braces += 1;
+          opened = true;
+        }
+        '}' => {
+          braces = braces.saturating_sub(1);
+          if opened && braces == 0 {
+            return i;
+          }
+        }
+        '(' | '[' => nesting += 1,
+        ')' | ']' => nesting = nesting.saturating_sub(1),
+        ';' if braces == 0 && nesting == 0 => return i,
+        _ => {}
+      }
+    }
+
+    if !opened && nesting == 0 && text.trim_end().ends_with(':') {
+      return indented_block_end(lines, line, i);
+    }
+  }
+
+  lines.len() - 1
+}
+
+// Last line indented deeper than the definition at `line`, whose header ends at `header_end`
+fn indented_block_end(lines: &[&str], line: usize, header_end: usize) -> usize {
+  let indentation = |text: &str| text.len() - text.trim_start().len();
+  let base = indentation(lines[line]);
+  let mut end = header_end;
+
+  for (i, text) in lines.iter().enumerate().skip(header_end + 1) {
+    if text.trim().is_empty() {
+      continue;
+    }
+
+    if indentation(text) <= base {
+      break;
+    }
+
+    end = i;
+  }
+
+  end
+}
+
+// First line of the doc comments, attributes and decorators right above `line`, not going above `floor`
+fn preamble_start(lines: &[&str], line: usize, floor: usize) -> usize {
+  let mut start = line;
+
+  while start > floor && ["//", "/*", "*", "#", "@"].iter().any(|prefix| lines[start - 1].trim_start().starts_with(prefix)) {
+    start -= 1;
+  }
+
+  start
+}
+
+// A path that names an existing file is taken literally even with glob characters in it, like the `[id]` of a
+// Next.js route
+fn is_pattern(root: &Path, file: &ContextFile) -> bool {
+  file.content.is_none() && (file.path.starts_with('!') || file.path.contains(['*', '?', '[', '{'])) && !root.join(&file.path).exists()
+}
+
+// Replaces every glob pattern with the files it matches, in file name order. Files listed explicitly or matched by an
+// earlier pattern aren't repeated, and `!` patterns remove files from the matches of all the others
+fn expand_patterns(root: &Path, files: Vec<ContextFile>, max_matches: usize, notes: &mut Vec<ContextFileNote>) -> Vec<ContextFile> {
+  let mut excludes = GlobSetBuilder::new();
+  for file in files.iter().filter(|file| is_pattern(root, file)) {
+    if let Some(pattern) = file.path.strip_prefix('!') {
+      match glob(pattern) {
+        Ok(glob) => {
+          excludes.add(glob);
+        }
+        Err(reason) => notes.push(ContextFileNote::Skipped { path: file.path.clone(), reason }),
+      }
+    }
+  }
+  let excludes = excludes.build().unwrap_or_else(|_| GlobSet::empty());
+
+  let candidates = if files.iter().any(|file| is_pattern(root, file) && !file.path.starts_with('!')) { walk(root, &excludes) } else { Vec::new() };
+  let mut seen: HashSet<String> = files.iter().filter(|file| !is_pattern(root, file)).map(|file| normalize(&file.path).to_string()).collect();
+  let mut matched = 0;
+  let mut expanded = Vec::new();
+
+  for file in files {
+    if !is_pattern(root, &file) {
+      expanded.push(file);
+      continue;
+    }
+
+    if file.path.starts_with('!') {
+      continue;
+    }
+
+    let matcher = match glob(&file.path) {
+      Ok(glob) => glob.compile_matcher(),
+      Err(reason) => {
+        notes.push(ContextFileNote::Skipped { path: file.path, reason });
+        continue;
+      }
+    };
+
+    let matches = matching(&candidates, &matcher);
+    if matches.is_empty() {
+      notes.push(ContextFileNote::Skipped { path: file.path, reason: "no files matched".to_string() });
+      continue;
+    }
+
+    let mut dropped = 0;
+    for path in matches.into_iter().filter(|path| seen.insert(path.to_string())) {
+      if matched == max_matches {
+        dropped += 1;
+        continue;
+      }
+
+      matched += 1;
+      expanded.push(ContextFile { path: path.to_string(), matched: true, ..Default::default() });
+    }
+
+    if dropped > 0 {
+      let reason = format!("{} more matches left out, over the limit of {} matched files", dropped, max_matches);
+      notes.push(ContextFileNote::Skipped { path: file.path, reason });
+    }
+  }
+
+  expanded
+}
+
+fn glob(pattern: &str) -> Result<globset::Glob, String> {
+  // `*` stays within one directory, as in .gitignore; `**` crosses them
+  GlobBuilder::new(normalize(pattern)).literal_separator(true).build().map_err(|e| format!("invalid pattern: {}", e))
+}
+
+fn matching<'a>(candidates: &'a [String], matcher: &GlobMatcher) -> Vec<&'a str> {
+  candidates.iter().filter(|path| matcher.is_match(path.as_str())).map(String::as_str).collect()
+}
+
+fn normalize(path: &str) -> &str {
+  path.trim_start_matches("./")
+}
+
+// Every file under `root` that .gitignore, .codechoignore and the `!` patterns leave, relative to `root` and sorted by
+// file name within each directory. Symlinked directories aren't followed, so the walk stays inside `root`
+fn walk(root: &Path, excludes: &GlobSet) -> Vec<String> {
+  WalkBuilder::new(root)
+    .hidden(false)
+    .require_git(false)
+    .add_custom_ignore_filename(IGNORE_FILE)
+    .filter_entry(|entry| entry.file_name() != ".git")
+    .sort_by_file_name(|a, b| a.cmp(b))
+    .build()
+    .filter_map(Result::ok)
+    .filter(|entry| entry.file_type().is_some_and(|file_type| file_type.is_file() || file_type.is_symlink()))
+    .filter_map(|entry| entry.path().strip_prefix(root).ok().map(|path| path.to_string_lossy().replace('\\', "/")))
+    .filter(|path| !excludes.is_match(path.as_str()))
+    .collect()
+}
+
+struct ReadFile {
+  content: String,
+  // Size of the whole file on disk
+  size: u64,
+}
+
+// Reads up to `limit` bytes of a text file inside `root`, cutting at a character boundary
+fn read_file(root: &Path, path: &str, limit: u64) -> Result<ReadFile, String> {
+  let path = resolve(root, path)?;
+
+  let mut file = File::open(&path).map_err(|e| e.to_string())?;
+  let metadata = file.metadata().map_err(|e| e.to_string())?;
+  if !metadata.is_file() {
+    return Err("not a regular file".to_string());
+  }
+
+  // One byte past the limit shows whether a character was cut in half
+  let mut bytes = Vec::new();
+  (&mut file).take(limit.saturating_add(1)).read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+  let size = metadata.len().max(bytes.len() as u64);
+
+  if bytes[..bytes.len().min(BINARY_SNIFF_BYTES)].contains(&0) {
+    return Err("binary file".to_string());
+  }
+
+  bytes.truncate(limit as usize);
+  let content = match String::from_utf8(bytes) {
+    Ok(content) => content,
+    // Only an incomplete character at the cut is fine; anything else isn't UTF-8 text
+    Err(e) if e.utf8_error().error_len().is_none() => {
+      let valid = e.utf8_error().valid_up_to();
+      let mut bytes = e.into_bytes();
+      bytes.truncate(valid);
+      String::from_utf8(bytes).unwrap_or_default()
+    }
+    Err(_) => return Err("binary file".to_string()),
+  };
+
+  Ok(ReadFile { content, size })
+}
+
+// Resolves `path` against `root`, following symlinks, and refuses anything that ends up outside of it
+fn resolve(root: &Path, path: &str) -> Result<PathBuf, String> {
+  let resolved = root.join(path).canonicalize().map_err(|e| e.to_string())?;
+
+  if !resolved.starts_with(root) {
+    return Err("outside the working directory".to_string());
+  }
+
+  Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The selected content and its 1-based line range
+  fn select_symbol(content: &str, symbol: &str) -> (String, usize, usize) {
+    let mut file = ContextFile { symbol: Some(symbol.to_string()), ..Default::default() };
+    let selected = select(&mut file, content).unwrap();
+
+    (selected, file.start_line.unwrap(), file.end_line.unwrap())
+  }
+
+  #[test]
+  fn finds_a_method_inside_its_impl() {
+    let content = "struct Config {\n  path: String,\n}\n\nimpl Config {\n  fn new() -> Self {\n    Self { path: String::new() }\n  }\n\n  /// Where it was read from\n  fn load(&self) -> String {\n    self.path.clone()\n  }\n}\n";
+
+    assert_eq!(
+      select_symbol(content, "Config::load"),
+      ("  /// Where it was read from\n  fn load(&self) -> String {\n    self.path.clone()\n  }\n".to_string(), 10, 13)
+    );
+  }
+
+  #[test]
+  fn prefers_the_least_indented_definition() {
+    let content = "fn main() {\n  let helper = helper();\n  fn helper() -> u32 {\n    1\n  }\n}\n\nfn helper() -> u32 {\n  2\n}\n";
+
+    assert_eq!(select_symbol(content, "helper"), ("fn helper() -> u32 {\n  2\n}\n".to_string(), 8, 10));
+  }
+
+  #[test]
+  fn python_method_ends_with_its_indented_block() {
+    let content = "class Parser:\n    def __init__(self):\n        self.items = []\n\n    def parse(self, text):\n        for line in text.splitlines():\n            self.items.append(line)\n\n        return self.items\n\n    def reset(self):\n        self.items = []\n";
+
+    let (selected, start, end) = select_symbol(content, "Parser.parse");
+    assert_eq!((start, end), (5, 9));
+    assert_eq!(
+      selected,
+      "    def parse(self, text):\n        for line in text.splitlines():\n            self.items.append(line)\n\n        return self.items\n"
+    );
+  }
+
+  #[test]
+  fn semicolon_inside_the_signature_does_not_end_the_definition() {
+    let content = "fn f(x: [u8; 4]) {\n  x[0];\n}\n\nfn g() {}\n";
+
+    assert_eq!(select_symbol(content, "f"), ("fn f(x: [u8; 4]) {\n  x[0];\n}\n".to_string(), 1, 3));
+  }
+
+  fn file(path: &str, bytes: usize) -> ContextFile {
+    ContextFile { path: path.to_string(), content: Some("x".repeat(bytes)), ..Default::default() }
+  }
+
+  // Packs the files into `budget` tokens, with a prompt that is nothing but their content
+  fn pack_files(files: Vec<ContextFile>, budget: u64, notes: &mut Vec<ContextFileNote>) -> Vec<ContextFile> {
+    let prompt: String = files.iter().filter_map(|file| file.content.as_deref()).collect();
+    let mut context = Context { files: Some(files), ..Default::default() };
+
+    assert!(pack(&mut context, &prompt, budget, notes));
+    context.files.unwrap()
+  }
+
+  #[test]
+  fn pack_keeps_explicit_files_then_higher_priorities_then_smaller_files() {
+    let files = vec![
+      ContextFile { matched: true, priority: Some(5), ..file("glob.rs", 400) },
+      file("low.rs", 400),
+      ContextFile { priority: Some(1), ..file("high.rs", 800) },
+      file("small.rs", 200),
+    ];
+
+    let mut notes = Vec::new();
+    let files = pack_files(files, 350, &mut notes);
+
+    let kept: Vec<&str> = files.iter().filter(|file| file.content.is_some()).map(|file| file.path.as_str()).collect();
+    assert_eq!(kept, ["low.rs", "high.rs", "small.rs"]);
+    assert_eq!(notes, [ContextFileNote::Dropped { path: "glob.rs".to_string(), estimated_tokens: 100 }]);
+  }
+
+  #[test]
+  fn pack_truncates_only_when_enough_tokens_are_left() {
+    let mut notes = Vec::new();
+    let files = pack_files(vec![file("keep.rs", 400), file("big.rs", 2000)], 100 + MIN_TRUNCATED_TOKENS, &mut notes);
+
+    let included = (MIN_TRUNCATED_TOKENS - MARKER_TOKENS) * BYTES_PER_TOKEN;
+    assert_eq!(files[1].content.as_ref().map(String::len), Some(included as usize));
+    assert_eq!(files[1].truncated_from, Some(2000));
+    assert_eq!(notes, [ContextFileNote::Truncated { path: "big.rs".to_string(), included_bytes: included, file_bytes: 2000 }]);
+
+    let mut notes = Vec::new();
+    let files = pack_files(vec![file("keep.rs", 400), file("big.rs", 2000)], 100 + MIN_TRUNCATED_TOKENS - 1, &mut notes);
+
+    assert_eq!(files[1].content, None);
+    assert_eq!(notes, [ContextFileNote::Dropped { path: "big.rs".to_string(), estimated_tokens: 500 }]);
+  }
+
+  #[test]
+  fn pack_replaces_the_truncation_note_of_a_dropped_file() {
+    let truncated = ContextFile { truncated_from: Some(5000), ..file("big.rs", 2000) };
+    let mut notes = vec![ContextFileNote::Truncated { path: "big.rs".to_string(), included_bytes: 2000, file_bytes: 5000 }];
+    let files = pack_files(vec![truncated], 100, &mut notes);
+
+    assert_eq!(files[0].truncated_from, None);
+    assert_eq!(notes, [ContextFileNote::Dropped { path: "big.rs".to_string(), estimated_tokens: 500 }]);
+  }
+
+  fn load(root: &Path, paths: &[&str], config: &ContextConfig) -> (Vec<ContextFile>, Vec<ContextFileNote>) {
+    let files = paths.iter().map(|path| ContextFile { path: path.to_string(), ..Default::default() }).collect();
+    let mut context = Context { working_dir: root.to_str().unwrap().to_string(), files: Some(files), ..Default::default() };
+    let notes = load_files(&mut context, config);
+
+    (context.files.unwrap(), notes)
+  }
+
+  #[test]
+  fn existing_paths_with_glob_characters_are_taken_literally() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("app/[id]")).unwrap();
+    std::fs::write(dir.path().join("app/[id]/page.tsx"), "export default function Page() {}\n").unwrap();
+    std::fs::write(dir.path().join("{legacy}.rs"), "fn legacy() {}\n").unwrap();
+
+    let (files, notes) = load(dir.path(), &["app/[id]/page.tsx", "{legacy}.rs"], &ContextConfig::default());
+    assert_eq!(notes, []);
+    assert_eq!(files[0].content.as_deref(), Some("export default function Page() {}\n"));
+    assert_eq!(files[1].content.as_deref(), Some("fn legacy() {}\n"));
+
+    // Paths that don't exist are still patterns
+    let (files, notes) = load(dir.path(), &["app/*/page.tsx"], &ContextConfig::default());
+    assert_eq!(notes, []);
+    assert_eq!(files.iter().map(|file| file.path.as_str()).collect::<Vec<_>>(), ["app/[id]/page.tsx"]);
+    assert!(files[0].matched);
+  }
+
+  fn skipped_reason(notes: &[ContextFileNote], path: &str) -> String {
+    match notes.iter().find(|note| matches!(note, ContextFileNote::Skipped { path: skipped, .. } if skipped == path)) {
+      Some(ContextFileNote::Skipped { reason, .. }) => reason.clone(),
+      _ => panic!("{} was not skipped: {:?}", path, notes),
+    }
+  }
+
+  #[test]
+  fn paths_outside_the_working_directory_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = dir.path().canonicalize().unwrap();
+    let root = outside.join("work");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(outside.join("secret.txt"), "secret\n").unwrap();
+    std::fs::write(root.join("main.rs"), "fn main() {}\n").unwrap();
+
+    let secret = outside.join("secret.txt");
+    assert_eq!(resolve(&root, "../secret.txt"), Err("outside the working directory".to_string()));
+    assert_eq!(resolve(&root, secret.to_str().unwrap()), Err("outside the working directory".to_string()));
+    assert_eq!(resolve(&root, "../work/main.rs"), Ok(root.join("main.rs")));
+    assert_eq!(resolve(&root, root.join("main.rs").to_str().unwrap()), Ok(root.join("main.rs")));
+
+    let (files, notes) = load(&root, &["../secret.txt", secret.to_str().unwrap()], &ContextConfig::default());
+    assert!(files.iter().all(|file| file.content.is_none()));
+    assert_eq!(skipped_reason(&notes, "../secret.txt"), "outside the working directory");
+    assert_eq!(skipped_reason(&notes, secret.to_str().unwrap()), "outside the working directory");
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks_are_followed_only_inside_the_working_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = dir.path().canonicalize().unwrap();
+    let root = outside.join("work");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(outside.join("secret.txt"), "secret\n").unwrap();
+    std::fs::write(root.join("main.rs"), "fn main() {}\n").unwrap();
+    std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("escape.txt")).unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("parent")).unwrap();
+    std::os::unix::fs::symlink(root.join("main.rs"), root.join("alias.rs")).unwrap();
+
+    assert_eq!(resolve(&root, "escape.txt"), Err("outside the working directory".to_string()));
+    assert_eq!(resolve(&root, "parent/secret.txt"), Err("outside the working directory".to_string()));
+    assert_eq!(resolve(&root, "alias.rs"), Ok(root.join("main.rs")));
+
+    let (files, notes) = load(&root, &["escape.txt", "alias.rs"], &ContextConfig::default());
+    assert_eq!(skipped_reason(&notes, "escape.txt"), "outside the working directory");
+    assert_eq!(files[0].content, None);
+    assert_eq!(files[1].content.as_deref(), Some("fn main() {}\n"));
+  }
+
+  #[test]
+  fn binary_files_are_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    std::fs::write(root.join("image.png"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+    std::fs::write(root.join("latin1.txt"), b"caf\xe9 au lait\n").unwrap();
+
+    assert_eq!(read_file(&root, "image.png", 1000).err(), Some("binary file".to_string()));
+    assert_eq!(read_file(&root, "latin1.txt", 1000).err(), Some("binary file".to_string()));
+
+    let (_, notes) = load(&root, &["image.png"], &ContextConfig::default());
+    assert_eq!(skipped_reason(&notes, "image.png"), "binary file");
+  }
+
+  #[test]
+  fn files_are_cut_to_the_per_file_and_total_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    // The 10 byte limit falls inside the two byte `é`
+    std::fs::write(root.join("a.txt"), "aaaaaaaaaé tail\n").unwrap();
+    std::fs::write(root.join("b.txt"), "b".repeat(20)).unwrap();
+    std::fs::write(root.join("c.txt"), "c").unwrap();
+
+    let read = read_file(&root, "a.txt", 10).unwrap();
+    assert_eq!((read.content.as_str(), read.size), ("aaaaaaaaa", 17));
+
+    let config = ContextConfig { max_file_bytes: 10, max_total_bytes: 16, ..Default::default() };
+    let (files, notes) = load(&root, &["a.txt", "b.txt", "c.txt"], &config);
+
+    assert_eq!(files[0].content.as_deref(), Some("aaaaaaaaa"));
+    // Only what is left of the total after `a.txt` is read
+    assert_eq!(files[1].content.as_deref(), Some("bbbbbbb"));
+    assert_eq!(files[2].content, None);
+    assert_eq!(
+      notes,
+      [
+        ContextFileNote::Truncated { path: "a.txt".to_string(), included_bytes: 9, file_bytes: 17 },
+        ContextFileNote::Truncated { path: "b.txt".to_string(), included_bytes: 7, file_bytes: 20 },
+        ContextFileNote::Skipped { path: "c.txt".to_string(), reason: "the total context size limit was reached".to_string() },
+      ]
+    );
+  }
+
+  #[test]
+  fn window_budget_leaves_headroom() {
+    assert_eq!(window_budget(272000), 136000);
+  }
+
+  #[test]
+  fn line_range_is_clamped_to_the_file() {
+    assert_eq!(line_range(10, Some(2), Some(20)), Ok((2, 10)));
+    assert_eq!(line_range(10, None, None), Ok((1, 10)));
+  }
+
+  #[test]
+  fn line_range_rejects_lines_past_the_end_or_reversed() {
+    assert_eq!(line_range(3, Some(5), None), Err("start_line 5 is past the end of the file (3 lines)".to_string()));
+    assert_eq!(line_range(10, Some(6), Some(4)), Err("start_line 6 is after end_line 4".to_string()));
+  }
+}
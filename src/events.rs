@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::types::*;
+
+// The `codex exec --json` output formats codecho understands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventSchema {
+  // `{"id": ..., "msg": {"type": ...}}` events preceded by a config header line
+  V1,
+  // `thread.*`, `turn.*` and `item.*` events introduced with the experimental JSON output
+  V2,
+}
+
+// Turns codex stdout lines into `CodexMessage`s whatever schema the binary speaks, so the rest of codecho
+// only ever sees V1-shaped events
+#[derive(Debug, Default)]
+pub struct EventDecoder {
+  schema: Option<EventSchema>,
+}
+
+impl EventDecoder {
+  // Without a schema the first JSON line decides it
+  pub fn new(schema: Option<EventSchema>) -> Self {
+    Self { schema }
+  }
+
+  pub fn decode(&mut self, line: &str) -> Vec<CodexMessage> {
+    let Ok(value) = serde_json::from_str::<Value>(line) else {
+      return Vec::new();
+    };
+
+    if !value.is_object() {
+      return Vec::new();
+    }
+
+    let schema = *self.schema.get_or_insert_with(|| {
+      let schema = detect_schema(&value);
+      tracing::debug!("Decoding codex events with the {:?} schema", schema);
+      schema
+    });
+
+    match schema {
+      EventSchema::V1 => serde_json::from_value::<CodexMessage>(value).into_iter().collect(),
+      EventSchema::V2 => match ThreadEvent::deserialize(&value) {
+        Ok(event) => normalize(event, value),
+        Err(_) => vec![event(EventMsg::Unknown(value))],
+      },
+    }
+  }
+}
+
+fn detect_schema(value: &Value) -> EventSchema {
+  if value.get("msg").is_none() && value.get("type").is_some_and(Value::is_string) { EventSchema::V2 } else { EventSchema::V1 }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ThreadEvent {
+  #[serde(rename = "thread.started")]
+  ThreadStarted { thread_id: String },
+  #[serde(rename = "turn.started")]
+  TurnStarted {},
+  #[serde(rename = "turn.completed")]
+  TurnCompleted { usage: Option<ThreadUsage> },
+  #[serde(rename = "turn.failed")]
+  TurnFailed { error: ThreadError },
+  #[serde(rename = "item.started")]
+  ItemStarted { item: ThreadItem },
+  #[serde(rename = "item.completed")]
+  ItemCompleted { item: ThreadItem },
+  #[serde(rename = "error")]
+  Error { message: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct ThreadUsage {
+  input_tokens: u64,
+  cached_input_tokens: Option<u64>,
+  output_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ThreadError {
+  message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ThreadItem {
+  id: String,
+  #[serde(flatten)]
+  details: ItemDetails,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ItemDetails {
+  AgentMessage {
+    text: String,
+  },
+  Reasoning {
+    text: String,
+  },
+  CommandExecution {
+    command: String,
+    #[serde(default)]
+    aggregated_output: String,
+    exit_code: Option<i32>,
+  },
+  FileChange {
+    changes: Vec<FileUpdate>,
+    status: String,
+  },
+  McpToolCall {
+    server: String,
+    tool: String,
+  },
+  Error {
+    message: String,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileUpdate {
+  path: String,
+  kind: String,
+}
+
+fn event(msg: EventMsg) -> CodexMessage {
+  CodexMessage { msg: Some(msg), ..Default::default() }
+}
+
+fn normalize(thread_event: ThreadEvent, raw: Value) -> Vec<CodexMessage> {
+  let msgs = match thread_event {
+    ThreadEvent::ThreadStarted { thread_id } => vec![EventMsg::SessionConfigured { session_id: thread_id, model: None }],
+    ThreadEvent::TurnStarted {} => vec![EventMsg::TaskStarted { model_context_window: None }],
+    ThreadEvent::TurnCompleted { usage } => {
+      let info = usage.map(|usage| TokenUsageInfo {
+        total_token_usage: Some(TokenUsage {
+          input_tokens: usage.input_tokens,
+          cached_input_tokens: usage.cached_input_tokens,
+          output_tokens: usage.output_tokens,
+          reasoning_output_tokens: None,
+          total_tokens: usage.input_tokens + usage.output_tokens,
+        }),
+        last_token_usage: None,
+        model_context_window: None,
+      });
+
+      vec![EventMsg::TokenCount { info }, EventMsg::TaskComplete { last_agent_message: None }]
+    }
+    ThreadEvent::TurnFailed { error } => vec![EventMsg::Error { message: error.message }],
+    ThreadEvent::Error { message } => vec![EventMsg::Error { message }],
+    ThreadEvent::ItemStarted { item } => match item.details {
+      ItemDetails::CommandExecution { command, .. } => vec![EventMsg::ExecCommandBegin { call_id: item.id, command: vec![command], cwd: None }],
+      ItemDetails::McpToolCall { server, tool } => {
+        vec![EventMsg::McpToolCallBegin { call_id: item.id, invocation: McpInvocation { server, tool, arguments: None } }]
+      }
+      _ => vec![EventMsg::Unknown(raw)],
+    },
+    ThreadEvent::ItemCompleted { item } => match item.details {
+      ItemDetails::AgentMessage { text } => vec![EventMsg::AgentMessage { message: text }],
+      ItemDetails::Reasoning { text } => vec![EventMsg::AgentReasoning { text }],
+      ItemDetails::CommandExecution { aggregated_output, exit_code, .. } => vec![EventMsg::ExecCommandEnd {
+        call_id: item.id,
+        exit_code: exit_code.unwrap_or(-1),
+        stdout: String::new(),
+        stderr: String::new(),
+        aggregated_output: Some(aggregated_output),
+        duration: None,
+      }],
+      // V2 only reports patches once they are applied, so both halves are emitted together
+      ItemDetails::FileChange { changes, status } => {
+        let changes: BTreeMap<_, _> = changes.into_iter().map(|change| (change.path, json!({ "kind": change.kind }))).collect();
+
+        vec![
+          EventMsg::PatchApplyBegin { call_id: item.id.clone(), auto_approved: true, changes },
+          EventMsg::PatchApplyEnd { call_id: item.id, stdout: String::new(), stderr: String::new(), success: status == "completed" },
+        ]
+      }
+      ItemDetails::McpToolCall { server, tool } => {
+        vec![EventMsg::McpToolCallEnd { call_id: item.id, invocation: McpInvocation { server, tool, arguments: None }, duration: None, result: None }]
+      }
+      ItemDetails::Error { message } => vec![EventMsg::StreamError { message }],
+      ItemDetails::Other => vec![EventMsg::Unknown(raw)],
+    },
+  };
+
+  msgs.into_iter().map(event).collect()
+}
+
+// Decodes the JSONL events of a configured CLI agent by rewriting each one into the codex event it maps to
+#[derive(Debug)]
+pub struct EventMapper {
+  type_pointer: String,
+  mappings: Vec<EventMapping>,
+}
+
+impl EventMapper {
+  pub fn new(type_pointer: String, mappings: Vec<EventMapping>) -> Self {
+    Self { type_pointer, mappings }
+  }
+
+  pub fn decode(&self, line: &str) -> Vec<CodexMessage> {
+    let Ok(value) = serde_json::from_str::<Value>(line) else {
+      return Vec::new();
+    };
+
+    let kind = value.pointer(&self.type_pointer).and_then(Value::as_str);
+    let Some(mapping) = self.mappings.iter().find(|mapping| Some(mapping.matches.as_str()) == kind) else {
+      return vec![event(EventMsg::Unknown(value))];
+    };
+
+    let mut mapped = json!({ "type": mapping.event });
+    for (target, source) in &mapping.fields {
+      if let Some(field) = value.pointer(source) {
+        set_pointer(&mut mapped, target, field.clone());
+      }
+    }
+
+    // A mapping that doesn't produce a valid codex event keeps the original so nothing is lost
+    match serde_json::from_value::<EventMsg>(mapped) {
+      Ok(EventMsg::Unknown(_)) | Err(_) => vec![event(EventMsg::Unknown(value))],
+      Ok(msg) => vec![event(msg)],
+    }
+  }
+}
+
+// Creates the objects along `pointer` as needed
+fn set_pointer(target: &mut Value, pointer: &str, field: Value) {
+  let mut current = target;
+
+  for key in pointer.split('/').skip(1) {
+    if !current.is_object() {
+      *current = json!({});
+    }
+
+    current = current.as_object_mut().unwrap().entry(key.replace("~1", "/").replace("~0", "~")).or_insert(Value::Null);
+  }
+
+  *current = field;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The decoded events, as the JSON they are logged and forwarded as
+  fn decode(decoder: &mut EventDecoder, line: &str) -> Vec<Value> {
+    decoder.decode(line).into_iter().map(|message| serde_json::to_value(message.msg).unwrap()).collect()
+  }
+
+  fn assert_decodes(schema: EventSchema, cases: &[(&str, Vec<Value>)]) {
+    for (line, expected) in cases {
+      assert_eq!(&decode(&mut EventDecoder::new(Some(schema)), line), expected, "{}", line);
+    }
+  }
+
+  #[test]
+  fn v1_events_are_read_as_they_are() {
+    assert_decodes(
+      EventSchema::V1,
+      &[
+        (
+          r#"{"id":"0","msg":{"type":"session_configured","session_id":"abc","model":"gpt-5-codex"}}"#,
+          vec![json!({ "type": "session_configured", "session_id": "abc", "model": "gpt-5-codex" })],
+        ),
+        (r#"{"id":"1","msg":{"type":"agent_message","message":"done"}}"#, vec![json!({ "type": "agent_message", "message": "done" })]),
+        (
+          r#"{"id":"1","msg":{"type":"exec_command_begin","call_id":"c1","command":["ls"],"cwd":"/repo"}}"#,
+          vec![json!({ "type": "exec_command_begin", "call_id": "c1", "command": ["ls"], "cwd": "/repo" })],
+        ),
+        (r#"{"id":"1","msg":{"type":"task_complete","last_agent_message":"done"}}"#, vec![json!({ "type": "task_complete", "last_agent_message": "done" })]),
+        // The config header carries no event
+        (r#"{"model":"gpt-5-codex","sandbox":"read-only","workdir":"/repo"}"#, vec![Value::Null]),
+        (r#"{"id":"1","msg":{"type":"brand_new_event","detail":1}}"#, vec![json!({ "type": "brand_new_event", "detail": 1 })]),
+        ("not json", vec![]),
+        ("[1, 2]", vec![]),
+      ],
+    );
+  }
+
+  #[test]
+  fn v2_events_are_turned_into_v1_events() {
+    assert_decodes(
+      EventSchema::V2,
+      &[
+        (r#"{"type":"thread.started","thread_id":"abc"}"#, vec![json!({ "type": "session_configured", "session_id": "abc", "model": null })]),
+        (r#"{"type":"turn.started"}"#, vec![json!({ "type": "task_started", "model_context_window": null })]),
+        (
+          r#"{"type":"turn.completed","usage":{"input_tokens":100,"cached_input_tokens":40,"output_tokens":20}}"#,
+          vec![
+            json!({
+              "type": "token_count",
+              "info": {
+                "total_token_usage": { "input_tokens": 100, "cached_input_tokens": 40, "output_tokens": 20, "reasoning_output_tokens": null, "total_tokens": 120 },
+                "last_token_usage": null,
+                "model_context_window": null,
+              },
+            }),
+            json!({ "type": "task_complete", "last_agent_message": null }),
+          ],
+        ),
+        (r#"{"type":"turn.failed","error":{"message":"quota"}}"#, vec![json!({ "type": "error", "message": "quota" })]),
+        (
+          r#"{"type":"item.started","item":{"id":"item_1","type":"command_execution","command":"ls","aggregated_output":"","status":"in_progress"}}"#,
+          vec![json!({ "type": "exec_command_begin", "call_id": "item_1", "command": ["ls"], "cwd": null })],
+        ),
+        (
+          r#"{"type":"item.completed","item":{"id":"item_1","type":"command_execution","command":"ls","aggregated_output":"Cargo.toml\n","exit_code":0,"status":"completed"}}"#,
+          vec![json!({
+            "type": "exec_command_end",
+            "call_id": "item_1",
+            "exit_code": 0,
+            "stdout": "",
+            "stderr": "",
+            "aggregated_output": "Cargo.toml\n",
+            "duration": null,
+          })],
+        ),
+        (
+          r#"{"type":"item.completed","item":{"id":"item_2","type":"file_change","changes":[{"path":"src/lib.rs","kind":"update"}],"status":"completed"}}"#,
+          vec![
+            json!({ "type": "patch_apply_begin", "call_id": "item_2", "auto_approved": true, "changes": { "src/lib.rs": { "kind": "update" } } }),
+            json!({ "type": "patch_apply_end", "call_id": "item_2", "stdout": "", "stderr": "", "success": true }),
+          ],
+        ),
+        (
+          r#"{"type":"item.completed","item":{"id":"item_3","type":"agent_message","text":"done"}}"#,
+          vec![json!({ "type": "agent_message", "message": "done" })],
+        ),
+        (
+          r#"{"type":"item.completed","item":{"id":"item_4","type":"reasoning","text":"thinking"}}"#,
+          vec![json!({ "type": "agent_reasoning", "text": "thinking" })],
+        ),
+        // Unknown event and item types are kept verbatim
+        (r#"{"type":"thread.paused","thread_id":"abc"}"#, vec![json!({ "type": "thread.paused", "thread_id": "abc" })]),
+        (
+          r#"{"type":"item.completed","item":{"id":"item_5","type":"web_search","query":"rust"}}"#,
+          vec![json!({ "type": "item.completed", "item": { "id": "item_5", "type": "web_search", "query": "rust" } })],
+        ),
+        (
+          r#"{"type":"item.started","item":{"id":"item_3","type":"agent_message","text":""}}"#,
+          vec![json!({ "type": "item.started", "item": { "id": "item_3", "type": "agent_message", "text": "" } })],
+        ),
+      ],
+    );
+  }
+
+  #[test]
+  fn the_first_json_line_decides_the_schema() {
+    let mut decoder = EventDecoder::new(None);
+    assert_eq!(decode(&mut decoder, "Reading prompt from stdin..."), Vec::<Value>::new());
+    assert_eq!(decode(&mut decoder, r#"{"type":"thread.started","thread_id":"abc"}"#)[0]["type"], "session_configured");
+    assert_eq!(decoder.schema, Some(EventSchema::V2));
+
+    let mut decoder = EventDecoder::new(None);
+    assert_eq!(decode(&mut decoder, r#"{"model":"gpt-5-codex","sandbox":"read-only","workdir":"/repo"}"#), [Value::Null]);
+    assert_eq!(decoder.schema, Some(EventSchema::V1));
+    // Once detected, a V2-looking line is not reinterpreted
+    assert_eq!(decode(&mut decoder, r#"{"type":"thread.started","thread_id":"abc"}"#), [Value::Null]);
+  }
+}
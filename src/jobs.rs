@@ -0,0 +1,423 @@
+use std::{
+  sync::{Arc, Mutex, OnceLock},
+  time::Instant,
+};
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+  backend::{AgentBackend, Backends},
+  scheduler::{QueueStateSender, Scheduler, WorkdirLocks},
+  store::{JobStore, PersistedJob},
+  types::*,
+};
+
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+const PROMPT_PREVIEW_CHARS: usize = 120;
+const FINISHED_JOB_TTL_HOURS: i64 = 24;
+
+#[derive(Debug)]
+pub struct JobManager {
+  backends: Backends,
+  scheduler: Arc<Scheduler>,
+  workdir_locks: WorkdirLocks,
+  store: Option<JobStore>,
+  jobs: DashMap<String, Arc<Job>>,
+  // Context window size by model, as reported by earlier runs
+  context_windows: DashMap<String, u64>,
+  shutdown: watch::Sender<ShutdownPhase>,
+}
+
+impl JobManager {
+  pub fn new(backends: Backends, scheduler: Scheduler, store: Option<JobStore>) -> Self {
+    Self {
+      backends,
+      scheduler: Arc::new(scheduler),
+      workdir_locks: WorkdirLocks::default(),
+      store,
+      jobs: DashMap::new(),
+      context_windows: DashMap::new(),
+      shutdown: watch::Sender::new(ShutdownPhase::Serving),
+    }
+  }
+
+  pub fn backends(&self) -> &Backends {
+    &self.backends
+  }
+
+  pub fn context_window(&self, model: &str) -> Option<u64> {
+    self.context_windows.get(model).map(|window| *window)
+  }
+
+  pub fn submit(
+    self: &Arc<Self>,
+    session_id: &str,
+    backend: Arc<dyn AgentBackend>,
+    request: CodexPromptRequest,
+    invocation: CodexInvocation,
+    context_files: Vec<ContextFileNote>,
+  ) -> Result<(Arc<Job>, broadcast::Receiver<CodexEvent>)> {
+    // Held until the job is registered, so a shutdown either finds the job or has already turned it away
+    let phase = self.shutdown.borrow();
+    if *phase != ShutdownPhase::Serving {
+      bail!("The server is shutting down and no longer accepts new codex runs");
+    }
+
+    self.prune_finished();
+
+    let (event_tx, event_rx) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+    let job = Arc::new(Job::new(session_id, &request, event_tx));
+    self.jobs.insert(job.id.clone(), job.clone());
+    drop(phase);
+
+    tokio::spawn({
+      let manager = self.clone();
+      let job = job.clone();
+
+      async move { manager.run(job, backend, request, invocation, context_files).await }
+    });
+
+    Ok((job, event_rx))
+  }
+
+  pub fn get(&self, job_id: &str) -> Option<Arc<Job>> {
+    self.jobs.get(job_id).map(|job| job.clone())
+  }
+
+  pub fn list(&self, session_id: &str) -> Vec<Arc<Job>> {
+    let mut jobs: Vec<_> = self.jobs.iter().filter(|job| job.session_id == session_id).map(|job| job.clone()).collect();
+    jobs.sort_by_key(|job| job.created_at);
+    jobs
+  }
+
+  pub async fn load_persisted(&self, job_id: &str, events_since: Option<usize>) -> Option<PersistedJob> {
+    let store = self.store.as_ref()?;
+
+    store.load(job_id, events_since).await.inspect_err(|e| tracing::error!("Failed to load job {} from the job store: {e:#}", job_id)).ok().flatten()
+  }
+
+  pub fn subscribe_shutdown(&self) -> watch::Receiver<ShutdownPhase> {
+    self.shutdown.subscribe()
+  }
+
+  // Stops taking new runs, lets running jobs finish until the drain timeout, then stops whatever is left
+  pub async fn shutdown(&self, drain_timeout: std::time::Duration) -> ShutdownReport {
+    let drain_timeout_ms = drain_timeout.as_millis() as u64;
+
+    // Stop admitting jobs before looking for pending ones, so none slips in after the snapshot. Sessions are only told
+    // once the number of jobs to wait for is known
+    self.shutdown.send_if_modified(|phase| {
+      *phase = ShutdownPhase::Draining { jobs: 0, drain_timeout_ms };
+      false
+    });
+
+    let pending: Vec<_> = self.jobs.iter().filter(|job| !job.status().is_finished()).map(|job| job.clone()).collect();
+    self.shutdown.send_replace(ShutdownPhase::Draining { jobs: pending.len(), drain_timeout_ms });
+
+    // Queued jobs have not started any work, so there is nothing worth waiting for
+    for job in pending.iter().filter(|job| job.status() == JobStatus::Queued) {
+      job.stop(RunFailure::ServerShutdown);
+    }
+
+    let wait_all = || futures::future::join_all(pending.iter().map(|job| job.wait()));
+
+    if tokio::time::timeout(drain_timeout, wait_all()).await.is_err() {
+      tracing::warn!("Drain timeout reached, stopping the remaining codex jobs");
+
+      for job in &pending {
+        job.stop(RunFailure::ServerShutdown);
+      }
+      wait_all().await;
+    }
+
+    self.backends.shutdown().await;
+
+    if let Some(store) = &self.store {
+      store.flush().await;
+    }
+
+    let stopped = pending.iter().filter(|job| job.result().is_some_and(|result| matches!(result.failure, Some(RunFailure::ServerShutdown)))).count();
+    let report = ShutdownReport { finished: pending.len() - stopped, stopped };
+    self.shutdown.send_replace(ShutdownPhase::Finished(report));
+
+    report
+  }
+
+  fn prune_finished(&self) {
+    let cutoff = Utc::now() - Duration::hours(FINISHED_JOB_TTL_HOURS);
+    self.jobs.retain(|_, job| job.finished_at().is_none_or(|finished_at| finished_at > cutoff));
+  }
+
+  async fn run(
+    &self,
+    job: Arc<Job>,
+    backend: Arc<dyn AgentBackend>,
+    request: CodexPromptRequest,
+    mut invocation: CodexInvocation,
+    context_files: Vec<ContextFileNote>,
+  ) {
+    let started_at = Instant::now();
+    let model = invocation.model.clone();
+    let sandbox_mode = invocation.sandbox_mode.to_string();
+
+    if let Some(store) = &self.store {
+      store.insert_job(&job.summary(), &job.session_id, &request, &invocation);
+    }
+
+    // Writable runs in the same directory would edit the same tree at once, so they take turns
+    let writable_dir = invocation.sandbox_mode.is_writable().then(|| invocation.working_dir.clone());
+    let (transcript, failure) = self.execute(&job, backend.as_ref(), &mut invocation, writable_dir.as_deref()).await;
+
+    if let Some(window) = transcript.model_context_window {
+      self.context_windows.insert(model.clone(), window);
+    }
+
+    let result = PromptResult {
+      final_message: transcript.agent_messages.last().cloned().unwrap_or_default(),
+      agent_messages: transcript.agent_messages,
+      token_usage: transcript.token_usage.and_then(|info| info.total_token_usage),
+      errors: transcript.errors,
+      model: transcript.model.unwrap_or(model),
+      sandbox_mode: transcript.sandbox.unwrap_or(sandbox_mode),
+      // Resumed sessions keep their id, even when the agent doesn't report it again
+      session_id: transcript.session_id.or(request.session_id),
+      context_files,
+      stderr: if failure.is_some() { transcript.exit.as_ref().map(|exit| exit.stderr_tail.clone()).unwrap_or_default() } else { Vec::new() },
+      duration_ms: started_at.elapsed().as_millis() as u64,
+      exit_code: transcript.exit.as_ref().and_then(|exit| exit.exit_code),
+      timed_out: matches!(failure, Some(RunFailure::TimedOut { .. })),
+      is_error: failure.is_some(),
+      failure,
+    };
+
+    if let Some(store) = &self.store {
+      store.finish_job(&job.id, JobStatus::from_result(&result), &result);
+    }
+
+    job.finish(result);
+  }
+
+  async fn execute(
+    &self,
+    job: &Job,
+    backend: &dyn AgentBackend,
+    invocation: &mut CodexInvocation,
+    writable_dir: Option<&str>,
+  ) -> (RunTranscript, Option<RunFailure>) {
+    // The timeout covers the whole job, including the time spent waiting for a free slot
+    let timeout_ms = invocation.timeout_ms;
+    let queued_at = Instant::now();
+
+    // Take the directory lock before a slot so a blocked job does not hold a slot it cannot use
+    let acquire = async {
+      let workdir = match writable_dir {
+        Some(working_dir) => Some(self.workdir_locks.lock(working_dir, &job.id, &job.queue).await),
+        None => None,
+      };
+
+      (workdir, self.scheduler.acquire(&job.session_id, job.queue.clone()).await)
+    };
+
+    let (_workdir, _permit) = tokio::select! {
+      acquired = acquire => acquired,
+      _ = job.cancel.cancelled() => return (RunTranscript::default(), Some(job.stop_reason())),
+      _ = tokio::time::sleep(std::time::Duration::from_millis(timeout_ms)) => return (RunTranscript::default(), Some(RunFailure::TimedOut { timeout_ms })),
+    };
+
+    job.status.send_replace(JobStatus::Running);
+    if let Some(store) = &self.store {
+      store.start_job(&job.id);
+    }
+    invocation.timeout_ms = timeout_ms.saturating_sub(queued_at.elapsed().as_millis() as u64);
+
+    let (msg_tx, mut msg_rx) = mpsc::unbounded_channel();
+
+    match backend.start(invocation, msg_tx, job.cancel.clone()).await {
+      Ok(()) => {
+        // Cancellation kills the process, so the transcript still ends once codex is gone
+        let transcript = self.collect_transcript(job, &mut msg_rx).await;
+        let failure = if job.cancel.is_cancelled() { Some(job.stop_reason()) } else { transcript.failure(timeout_ms, invocation.idle_timeout_ms) };
+        (transcript, failure)
+      }
+      Err(e) => (RunTranscript::default(), Some(RunFailure::SpawnFailed { message: format!("{e:#}") })),
+    }
+  }
+
+  async fn collect_transcript(&self, job: &Job, msg_rx: &mut mpsc::UnboundedReceiver<CodexEvent>) -> RunTranscript {
+    let mut transcript = RunTranscript::default();
+
+    // The channel only closes once stdout is drained and the process has exited
+    while let Some(event) = msg_rx.recv().await {
+      let message = match event {
+        CodexEvent::Message(message) => *message,
+        CodexEvent::Stderr(line) => {
+          job.relay(CodexEvent::Stderr(line));
+          continue;
+        }
+        CodexEvent::Exited(exit) => {
+          transcript.exit = Some(exit);
+          continue;
+        }
+      };
+
+      let seq = job.record(&message);
+      if let Some(store) = &self.store {
+        store.insert_event(&job.id, seq, &message);
+      }
+
+      let Some(msg_content) = message.msg else {
+        // The config header line carries the settings codex actually resolved
+        transcript.model = message.model.or(transcript.model);
+        transcript.sandbox = message.sandbox.or(transcript.sandbox);
+        transcript.session_id = message.session_id.or(transcript.session_id);
+        continue;
+      };
+
+      tracing::info!(job_id = %job.id, "Message content: {msg_content:#?}");
+
+      match msg_content {
+        EventMsg::AgentMessage { message } => transcript.agent_messages.push(message),
+        EventMsg::TaskStarted { model_context_window: Some(window) } => transcript.model_context_window = Some(window),
+        EventMsg::TokenCount { info: Some(info) } => {
+          transcript.model_context_window = info.model_context_window.or(transcript.model_context_window);
+          transcript.token_usage = Some(info);
+        }
+        EventMsg::Error { message } => transcript.errors.push(message),
+        EventMsg::SessionConfigured { session_id, .. } => transcript.session_id = Some(session_id),
+        _ => {}
+      }
+    }
+
+    transcript
+  }
+}
+
+#[derive(Debug)]
+pub struct Job {
+  pub id: String,
+  pub session_id: String,
+  pub created_at: DateTime<Utc>,
+  prompt_preview: String,
+  cancel: CancellationToken,
+  stop_reason: OnceLock<RunFailure>,
+  status: watch::Sender<JobStatus>,
+  queue: QueueStateSender,
+  inner: Mutex<JobInner>,
+}
+
+#[derive(Debug)]
+struct JobInner {
+  events: Vec<CodexMessage>,
+  event_tx: Option<broadcast::Sender<CodexEvent>>,
+  result: Option<PromptResult>,
+  finished_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+  fn new(session_id: &str, request: &CodexPromptRequest, event_tx: broadcast::Sender<CodexEvent>) -> Self {
+    Self {
+      id: Uuid::new_v4().to_string(),
+      session_id: session_id.to_string(),
+      created_at: Utc::now(),
+      prompt_preview: request.prompt.chars().take(PROMPT_PREVIEW_CHARS).collect(),
+      cancel: CancellationToken::new(),
+      stop_reason: OnceLock::new(),
+      status: watch::Sender::new(JobStatus::Queued),
+      queue: Arc::new(watch::Sender::new(None)),
+      inner: Mutex::new(JobInner { events: Vec::new(), event_tx: Some(event_tx), result: None, finished_at: None }),
+    }
+  }
+
+  pub fn status(&self) -> JobStatus {
+    *self.status.borrow()
+  }
+
+  pub fn subscribe_status(&self) -> watch::Receiver<JobStatus> {
+    self.status.subscribe()
+  }
+
+  pub fn subscribe_queue(&self) -> watch::Receiver<Option<QueueState>> {
+    self.queue.subscribe()
+  }
+
+  pub fn finished_at(&self) -> Option<DateTime<Utc>> {
+    self.inner.lock().unwrap().finished_at
+  }
+
+  pub fn result(&self) -> Option<PromptResult> {
+    self.inner.lock().unwrap().result.clone()
+  }
+
+  pub fn events_since(&self, cursor: usize) -> (Vec<CodexMessage>, usize) {
+    let inner = self.inner.lock().unwrap();
+    let events = inner.events.get(cursor..).unwrap_or_default().to_vec();
+    (events, inner.events.len())
+  }
+
+  pub fn cancel(&self) {
+    self.stop(RunFailure::Cancelled);
+  }
+
+  // The first reason wins, so a client cancel racing with shutdown keeps whichever came first
+  fn stop(&self, reason: RunFailure) {
+    let _ = self.stop_reason.set(reason);
+    self.cancel.cancel();
+  }
+
+  fn stop_reason(&self) -> RunFailure {
+    self.stop_reason.get().cloned().unwrap_or(RunFailure::Cancelled)
+  }
+
+  pub async fn wait(&self) -> Option<PromptResult> {
+    let _ = self.status.subscribe().wait_for(JobStatus::is_finished).await;
+    self.result()
+  }
+
+  pub fn summary(&self) -> JobSummary {
+    JobSummary {
+      job_id: self.id.clone(),
+      status: self.status(),
+      queue: self.queue.borrow().clone(),
+      prompt: self.prompt_preview.clone(),
+      created_at: self.created_at.to_rfc3339(),
+      finished_at: self.finished_at().map(|finished_at| finished_at.to_rfc3339()),
+    }
+  }
+
+  fn record(&self, message: &CodexMessage) -> usize {
+    let mut inner = self.inner.lock().unwrap();
+    inner.events.push(message.clone());
+
+    if let Some(event_tx) = &inner.event_tx {
+      let _ = event_tx.send(CodexEvent::Message(Box::new(message.clone())));
+    }
+
+    inner.events.len() - 1
+  }
+
+  // Passes an event on to live subscribers without keeping it in the job's event log
+  fn relay(&self, event: CodexEvent) {
+    if let Some(event_tx) = &self.inner.lock().unwrap().event_tx {
+      let _ = event_tx.send(event);
+    }
+  }
+
+  fn finish(&self, result: PromptResult) {
+    let status = JobStatus::from_result(&result);
+
+    {
+      let mut inner = self.inner.lock().unwrap();
+      inner.result = Some(result);
+      inner.finished_at = Some(Utc::now());
+      // Dropping the sender ends every live subscription
+      inner.event_tx = None;
+    }
+
+    self.status.send_replace(status);
+  }
+}
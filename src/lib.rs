@@ -0,0 +1,14 @@
+// The server's modules, shared by the `codecho` binary and the integration tests
+pub mod backend;
+pub mod capabilities;
+pub mod codex;
+pub mod codex_mcp;
+pub mod context;
+pub mod events;
+pub mod jobs;
+pub mod process;
+pub mod prompt;
+pub mod scheduler;
+pub mod server;
+pub mod store;
+pub mod types;
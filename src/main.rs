@@ -1,12 +1,24 @@
-mod codex;
-mod server;
-mod types;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use rmcp::transport::{StreamableHttpService, streamable_http_server::session::local::LocalSessionManager};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::server::CodexMcp;
+use codecho::{
+  backend::Backends,
+  capabilities::CodexInfo,
+  codex::CodexClient,
+  codex_mcp::CodexMcpBackend,
+  context::ContextLoader,
+  jobs::JobManager,
+  scheduler::Scheduler,
+  server::CodexMcp,
+  store::JobStore,
+  types::{BackendsConfig, CodexConfig, ContextConfig, SchedulerConfig, ShutdownConfig, StoreConfig},
+};
+
+// Gives sessions a moment to deliver the last prompt results and the shutdown notice before they are closed
+const SESSION_FLUSH_DELAY: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -20,20 +32,78 @@ async fn main() -> Result<()> {
 
   tracing::info!("Starting MCP server on port 9871");
 
+  // Refuse to start without a usable codex rather than failing every prompt later
+  let codex_config = CodexConfig::from_env();
+  let codex_info = CodexInfo::detect(&codex_config.binary).await?;
+  let backends = Backends::new(
+    BackendsConfig::from_env()?,
+    CodexClient::new(codex_config.clone(), codex_info.clone()),
+    CodexMcpBackend::new(codex_config.clone(), codex_info),
+    &codex_config,
+  )?;
+
+  // Keep serving without history rather than refusing to start
+  let store = JobStore::open(&StoreConfig::from_env()).inspect_err(|e| tracing::error!("Job persistence disabled: {e:#}")).ok();
+
+  // Jobs are shared across sessions so they outlive the request that started them
+  let jobs = Arc::new(JobManager::new(backends, Scheduler::new(SchedulerConfig::from_env()), store));
+
+  let context = Arc::new(ContextLoader::new(ContextConfig::from_env()));
+
+  let drain_timeout = Duration::from_millis(ShutdownConfig::from_env().drain_timeout_ms);
+  let session_manager = Arc::new(LocalSessionManager::default());
+
   let service = StreamableHttpService::new(
     //
-    || Ok(CodexMcp::new()),
-    LocalSessionManager::default().into(),
+    {
+      let jobs = jobs.clone();
+      move || Ok(CodexMcp::new(jobs.clone(), context.clone()))
+    },
+    session_manager.clone(),
     Default::default(),
   );
 
   let router = axum::Router::new().nest_service("/mcp", service);
   let tcp_listener = tokio::net::TcpListener::bind("localhost:9871".to_string()).await?;
   let _ = axum::serve(tcp_listener, router)
-    .with_graceful_shutdown(async {
-      tokio::signal::ctrl_c().await.unwrap();
+    .with_graceful_shutdown(async move {
+      shutdown_signal().await;
+
+      tracing::info!("Shutting down, waiting up to {:?} for unfinished codex jobs", drain_timeout);
+      let report = jobs.shutdown(drain_timeout).await;
+      tracing::info!("Shutdown complete: {}", report);
+
+      // Open sessions keep their event streams alive, which would otherwise hold up axum's graceful shutdown
+      tokio::time::sleep(SESSION_FLUSH_DELAY).await;
+      let sessions = std::mem::take(&mut *session_manager.sessions.write().await);
+      for (_, session) in sessions {
+        let _ = session.close().await;
+      }
     })
     .await;
 
   Ok(())
 }
+
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  let terminate = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut terminate) => {
+        terminate.recv().await;
+      }
+      Err(e) => {
+        tracing::error!("Failed to listen for SIGTERM: {e}");
+        std::future::pending::<()>().await;
+      }
+    }
+  };
+
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => {}
+    _ = terminate => {}
+  }
+}
@@ -0,0 +1,289 @@
+use crate::types::*;
+use anyhow::{Context, Result, anyhow};
+use std::collections::{HashSet, VecDeque};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+const STDERR_TAIL_LINES: usize = 50;
+const STDERR_LINE_MAX_CHARS: usize = 2000;
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Spawns an agent CLI and streams what it prints until it exits, enforcing the invocation's timeouts.
+// `decode` turns each stdout line into events; the stream always ends with exactly one `CodexEvent::Exited`
+pub fn spawn_supervised<D>(
+  mut command: Command,
+  invocation: &CodexInvocation,
+  mut decode: D,
+  kill_grace: Duration,
+  message_tx: mpsc::UnboundedSender<CodexEvent>,
+  cancel: CancellationToken,
+) -> Result<()>
+where
+  D: FnMut(&str) -> Vec<CodexMessage> + Send + 'static,
+{
+  let stdin = if invocation.prompt_on_stdin { Stdio::piped() } else { Stdio::null() };
+  command.stdin(stdin).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+  // Own process group so cancellation also reaches node and any shell commands the agent launched
+  #[cfg(unix)]
+  command.process_group(0);
+
+  let mut child = command.spawn().context("Failed to spawn agent process")?;
+
+  let stdout = child.stdout.take().ok_or_else(|| anyhow!("Failed to capture stdout"))?;
+  let stderr = child.stderr.take().ok_or_else(|| anyhow!("Failed to capture stderr"))?;
+
+  // Closing stdin once the prompt is written tells the agent it has all of it
+  if let Some(mut stdin) = child.stdin.take() {
+    let prompt = invocation.prompt.clone();
+
+    tokio::spawn(async move {
+      if let Err(e) = stdin.write_all(prompt.as_bytes()).await {
+        tracing::warn!("Failed to write the prompt to the agent's stdin: {}", e);
+      }
+    });
+  }
+
+  let timeout_ms = invocation.timeout_ms;
+  let idle_timeout_ms = invocation.idle_timeout_ms;
+  let (activity_tx, activity_rx) = watch::channel(0);
+
+  let mut stdout_task = tokio::spawn({
+    let message_tx = message_tx.clone();
+
+    async move {
+      let reader = BufReader::new(stdout);
+      let mut lines = reader.lines();
+      let mut in_flight = InFlightCalls::default();
+
+      while let Ok(Some(line)) = lines.next_line().await {
+        let messages = decode(&line);
+        messages.iter().for_each(|msg| in_flight.observe(msg));
+        if !messages.is_empty() {
+          activity_tx.send_replace(in_flight.len());
+        }
+
+        for msg in messages {
+          let _ = message_tx.send(CodexEvent::Message(Box::new(msg)));
+        }
+      }
+    }
+  });
+
+  // Spawn stderr reader, streaming lines as they come and keeping the last few around for failure reports
+  let stderr_tail = Arc::new(StdMutex::new(VecDeque::with_capacity(STDERR_TAIL_LINES)));
+  let mut stderr_task = tokio::spawn({
+    let stderr_tail = stderr_tail.clone();
+    let message_tx = message_tx.clone();
+
+    async move {
+      let reader = BufReader::new(stderr);
+      let mut lines = reader.lines();
+
+      while let Ok(Some(mut line)) = lines.next_line().await {
+        if let Some((cut, _)) = line.char_indices().nth(STDERR_LINE_MAX_CHARS) {
+          line.truncate(cut);
+          line.push_str("...");
+        }
+
+        tracing::warn!("Agent stderr: {}", line);
+
+        {
+          let mut stderr_tail = stderr_tail.lock().unwrap();
+          if stderr_tail.len() == STDERR_TAIL_LINES {
+            stderr_tail.pop_front();
+          }
+          stderr_tail.push_back(line.clone());
+        }
+
+        let _ = message_tx.send(CodexEvent::Stderr(line));
+      }
+    }
+  });
+
+  // Supervise the process: whichever of exit, timeout, inactivity or cancellation comes first decides how the run ends
+  tokio::spawn(async move {
+    let stop = tokio::select! {
+      _ = child.wait() => None,
+      _ = tokio::time::sleep(Duration::from_millis(timeout_ms)) => Some(StopCause::Timeout),
+      _ = idle_watchdog(activity_rx, idle_timeout_ms) => Some(StopCause::Idle),
+      _ = cancel.cancelled() => Some(StopCause::Cancelled),
+    };
+
+    match stop {
+      Some(StopCause::Timeout) => tracing::warn!("Agent run exceeded its {} ms timeout, killing agent process", timeout_ms),
+      Some(StopCause::Idle) => tracing::warn!("Agent emitted no events for {} ms, killing agent process", idle_timeout_ms),
+      Some(StopCause::Cancelled) => tracing::info!("Prompt cancelled, killing agent process"),
+      None => {}
+    }
+
+    if stop.is_some() {
+      terminate_process_group(&mut child, kill_grace).await;
+    }
+
+    // Waiting again returns the status the first wait already collected
+    let status = child.wait().await;
+
+    // Let the readers pass on what the agent wrote before exiting; anything still holding the pipes open is cut off
+    let _ = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, async {
+      let _ = (&mut stdout_task).await;
+      let _ = (&mut stderr_task).await;
+    })
+    .await;
+    stdout_task.abort();
+    stderr_task.abort();
+
+    let (exit_code, signal) = match &status {
+      Ok(status) => (status.code(), exit_signal(status)),
+      Err(_) => (None, None),
+    };
+
+    let error = match status {
+      Ok(status) if !status.success() => Some(format!("Agent process exited with status: {}", status)),
+      Ok(_) => None,
+      Err(e) => Some(format!("Failed to wait for agent process: {}", e)),
+    };
+
+    if let Some(error) = error {
+      let _ = message_tx.send(CodexEvent::Message(Box::new(CodexMessage { msg: Some(EventMsg::Error { message: error }), ..Default::default() })));
+    }
+
+    let stderr_tail = stderr_tail.lock().unwrap().iter().cloned().collect();
+    let _ = message_tx.send(CodexEvent::Exited(RunExit { exit_code, signal, stop, stderr_tail, call_error: None }));
+  });
+
+  Ok(())
+}
+
+// Commands and tool calls the agent started and hasn't reported the end of. They emit nothing until they finish, so
+// the agent isn't idle while any of them runs, however long a build or test suite takes
+#[derive(Debug, Default)]
+pub struct InFlightCalls(HashSet<String>);
+
+impl InFlightCalls {
+  pub fn observe(&mut self, message: &CodexMessage) {
+    match &message.msg {
+      Some(EventMsg::ExecCommandBegin { call_id, .. } | EventMsg::McpToolCallBegin { call_id, .. }) => {
+        self.0.insert(call_id.clone());
+      }
+      Some(EventMsg::ExecCommandEnd { call_id, .. } | EventMsg::McpToolCallEnd { call_id, .. }) => {
+        self.0.remove(call_id);
+      }
+      _ => {}
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+// Resolves once the agent has gone `idle_timeout_ms` without emitting an event while nothing it started was running;
+// never resolves when disabled. `activity` carries the number of calls in flight
+async fn idle_watchdog(mut activity: watch::Receiver<usize>, idle_timeout_ms: u64) {
+  if idle_timeout_ms == 0 {
+    return std::future::pending().await;
+  }
+
+  loop {
+    let changed = if *activity.borrow_and_update() > 0 {
+      Ok(activity.changed().await)
+    } else {
+      tokio::time::timeout(Duration::from_millis(idle_timeout_ms), activity.changed()).await
+    };
+
+    match changed {
+      Ok(Ok(())) => continue,
+      // Stdout is closed, so the agent is exiting and the supervisor sees the exit instead
+      Ok(Err(_)) => return std::future::pending().await,
+      Err(_) => return,
+    }
+  }
+}
+
+// Asks the agent and everything it launched to stop with SIGTERM, then SIGKILLs whatever is left after the grace period
+pub async fn terminate_process_group(child: &mut Child, grace: Duration) {
+  #[cfg(unix)]
+  if let Some(pid) = child.id() {
+    let group = pid as libc::pid_t;
+    signal_process_group(group, libc::SIGTERM);
+
+    let stopped = tokio::time::timeout(grace, async {
+      // Reap the agent itself first so an exited leader no longer counts as a live group member
+      let _ = child.wait().await;
+
+      while signal_process_group(group, 0) {
+        tokio::time::sleep(TERMINATE_POLL_INTERVAL).await;
+      }
+    })
+    .await;
+
+    if stopped.is_ok() {
+      return;
+    }
+
+    tracing::warn!("Agent process group {} ignored SIGTERM for {:?}, sending SIGKILL", group, grace);
+    signal_process_group(group, libc::SIGKILL);
+  }
+
+  #[cfg(not(unix))]
+  let _ = grace;
+
+  let _ = child.kill().await;
+}
+
+fn exit_signal(status: &ExitStatus) -> Option<i32> {
+  #[cfg(unix)]
+  return std::os::unix::process::ExitStatusExt::signal(status);
+
+  #[cfg(not(unix))]
+  {
+    let _ = status;
+    None
+  }
+}
+
+// Returns false once no process in the group is left to signal
+#[cfg(unix)]
+fn signal_process_group(group: libc::pid_t, signal: libc::c_int) -> bool {
+  // SAFETY: killpg only sends a signal; the group was created for this child by `process_group(0)`
+  unsafe { libc::killpg(group, signal) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(msg: EventMsg) -> CodexMessage {
+    CodexMessage { msg: Some(msg), ..Default::default() }
+  }
+
+  #[test]
+  fn calls_are_in_flight_from_begin_to_end() {
+    let mut in_flight = InFlightCalls::default();
+
+    in_flight.observe(&message(EventMsg::ExecCommandBegin { call_id: "c1".to_string(), command: vec!["cargo".to_string(), "test".to_string()], cwd: None }));
+    in_flight.observe(&message(EventMsg::AgentReasoning { text: "Waiting for the tests".to_string() }));
+    assert_eq!(in_flight.len(), 1);
+
+    in_flight.observe(&message(EventMsg::ExecCommandEnd {
+      call_id: "c1".to_string(),
+      exit_code: 0,
+      stdout: String::new(),
+      stderr: String::new(),
+      aggregated_output: None,
+      duration: None,
+    }));
+    assert!(in_flight.is_empty());
+  }
+}
@@ -0,0 +1,362 @@
+use std::{fmt::Write, path::Path};
+
+use crate::types::{CodexPromptRequest, ContextFile};
+
+// Language tags for fenced code blocks, by file extension
+const LANGUAGES: &[(&str, &str)] = &[
+  ("rs", "rust"),
+  ("py", "python"),
+  ("pyi", "python"),
+  ("js", "javascript"),
+  ("mjs", "javascript"),
+  ("cjs", "javascript"),
+  ("jsx", "jsx"),
+  ("ts", "typescript"),
+  ("mts", "typescript"),
+  ("cts", "typescript"),
+  ("tsx", "tsx"),
+  ("go", "go"),
+  ("java", "java"),
+  ("kt", "kotlin"),
+  ("kts", "kotlin"),
+  ("scala", "scala"),
+  ("swift", "swift"),
+  ("c", "c"),
+  ("h", "c"),
+  ("cc", "cpp"),
+  ("cpp", "cpp"),
+  ("cxx", "cpp"),
+  ("hpp", "cpp"),
+  ("hh", "cpp"),
+  ("cs", "csharp"),
+  ("rb", "ruby"),
+  ("php", "php"),
+  ("lua", "lua"),
+  ("dart", "dart"),
+  ("ex", "elixir"),
+  ("exs", "elixir"),
+  ("erl", "erlang"),
+  ("hs", "haskell"),
+  ("ml", "ocaml"),
+  ("r", "r"),
+  ("sh", "bash"),
+  ("bash", "bash"),
+  ("zsh", "zsh"),
+  ("fish", "fish"),
+  ("ps1", "powershell"),
+  ("sql", "sql"),
+  ("html", "html"),
+  ("htm", "html"),
+  ("css", "css"),
+  ("scss", "scss"),
+  ("vue", "vue"),
+  ("svelte", "svelte"),
+  ("json", "json"),
+  ("jsonl", "json"),
+  ("yaml", "yaml"),
+  ("yml", "yaml"),
+  ("toml", "toml"),
+  ("xml", "xml"),
+  ("ini", "ini"),
+  ("md", "markdown"),
+  ("proto", "protobuf"),
+  ("graphql", "graphql"),
+  ("tf", "hcl"),
+  ("diff", "diff"),
+  ("patch", "diff"),
+];
+
+// Files recognized by name rather than extension
+const FILE_NAME_LANGUAGES: &[(&str, &str)] = &[("Dockerfile", "dockerfile"), ("Makefile", "makefile"), ("CMakeLists.txt", "cmake")];
+
+// Shared by every backend so agents see the same context layout. The output only depends on the request, so the same
+// request always renders the same prompt
+pub fn assemble_prompt(request: CodexPromptRequest) -> String {
+  let mut prompt = String::new();
+  let context = request.context;
+
+  writeln!(&mut prompt, "# Context").unwrap();
+  writeln!(&mut prompt).unwrap();
+
+  writeln!(&mut prompt, "Working directory: {}", context.working_dir).unwrap();
+  writeln!(&mut prompt).unwrap();
+
+  if let Some(files) = &context.files {
+    writeln!(&mut prompt, "## Files").unwrap();
+    writeln!(&mut prompt).unwrap();
+
+    for file in files {
+      render_file(&mut prompt, file);
+    }
+  }
+
+  if let Some(variables) = &context.variables {
+    writeln!(&mut prompt, "## Variables").unwrap();
+    writeln!(&mut prompt).unwrap();
+
+    let mut variables: Vec<_> = variables.iter().collect();
+    variables.sort();
+
+    for (key, value) in variables {
+      writeln!(&mut prompt, "{}: {}", key, value).unwrap();
+    }
+
+    writeln!(&mut prompt).unwrap();
+  }
+
+  writeln!(&mut prompt, "## User Prompt").unwrap();
+  writeln!(&mut prompt).unwrap();
+  prompt.push_str(&request.prompt);
+
+  prompt
+}
+
+// A heading with the path and what part of the file follows, then its content in a fenced block
+fn render_file(prompt: &mut String, file: &ContextFile) {
+  write!(prompt, "### {}", file.path).unwrap();
+
+  if let Some(symbol) = &file.symbol {
+    write!(prompt, " `{}`", symbol).unwrap();
+  }
+
+  if let (Some(start), Some(end)) = (file.start_line, file.end_line) {
+    write!(prompt, " (lines {}-{})", start, end).unwrap();
+  }
+
+  writeln!(prompt).unwrap();
+  writeln!(prompt).unwrap();
+
+  let Some(content) = &file.content else {
+    writeln!(prompt, "Content not included.").unwrap();
+    writeln!(prompt).unwrap();
+    return;
+  };
+
+  let lines = match content.lines().count() {
+    1 => "1 line".to_string(),
+    count => format!("{} lines", count),
+  };
+
+  match file.truncated_from {
+    Some(size) => writeln!(prompt, "{} of {} bytes, {}", content.len(), size, lines).unwrap(),
+    None => writeln!(prompt, "{} bytes, {}", content.len(), lines).unwrap(),
+  }
+
+  let fence = fence(content);
+  writeln!(prompt, "{}{}", fence, language(&file.path).unwrap_or_default()).unwrap();
+
+  match (file.start_line, file.end_line) {
+    // Numbered so the agent can refer to the lines of the file, not of the excerpt
+    (Some(start), Some(end)) => {
+      let width = end.to_string().len();
+
+      for (number, line) in (start..).zip(content.lines()) {
+        writeln!(prompt, "{:>width$} | {}", number, line, width = width).unwrap();
+      }
+    }
+    _ => {
+      prompt.push_str(content);
+      if !content.ends_with('\n') {
+        prompt.push('\n');
+      }
+    }
+  }
+
+  writeln!(prompt, "{}", fence).unwrap();
+
+  if let Some(size) = file.truncated_from {
+    writeln!(prompt, "[... truncated, {} of {} bytes shown ...]", content.len(), size).unwrap();
+  }
+
+  writeln!(prompt).unwrap();
+}
+
+// A backtick fence longer than any backtick run in the content, so fences inside the file can't close the block
+fn fence(content: &str) -> String {
+  let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or_default();
+
+  "`".repeat(longest.max(2) + 1)
+}
+
+fn language(path: &str) -> Option<&'static str> {
+  let path = Path::new(path);
+  let file_name = path.file_name()?.to_str()?;
+
+  if let Some((_, language)) = FILE_NAME_LANGUAGES.iter().find(|(name, _)| *name == file_name) {
+    return Some(language);
+  }
+
+  let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+  LANGUAGES.iter().find(|(known, _)| *known == extension).map(|(_, language)| *language)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::types::Context;
+
+  fn request(files: Vec<ContextFile>) -> CodexPromptRequest {
+    CodexPromptRequest {
+      prompt: "Explain".to_string(),
+      context: Context { working_dir: "/repo".to_string(), files: Some(files), variables: None },
+      ..Default::default()
+    }
+  }
+
+  fn file(path: &str, content: &str) -> ContextFile {
+    ContextFile { path: path.to_string(), content: Some(content.to_string()), ..Default::default() }
+  }
+
+  #[test]
+  fn fence_is_longer_than_backtick_runs_in_the_content() {
+    let prompt = assemble_prompt(request(vec![file("notes.md", "````\ncode\n````\n")]));
+
+    assert_eq!(
+      prompt,
+      r#"# Context
+
+Working directory: /repo
+
+## Files
+
+### notes.md
+
+15 bytes, 3 lines
+`````markdown
+````
+code
+````
+`````
+
+## User Prompt
+
+Explain"#
+    );
+  }
+
+  #[test]
+  fn language_comes_from_the_extension_or_file_name() {
+    let prompt = assemble_prompt(request(vec![file("src/main.rs", "fn main() {}\n"), file("Dockerfile", "FROM rust\n"), file("LICENSE", "MIT\n")]));
+
+    assert_eq!(
+      prompt,
+      r#"# Context
+
+Working directory: /repo
+
+## Files
+
+### src/main.rs
+
+13 bytes, 1 line
+```rust
+fn main() {}
+```
+
+### Dockerfile
+
+10 bytes, 1 line
+```dockerfile
+FROM rust
+```
+
+### LICENSE
+
+4 bytes, 1 line
+```
+MIT
+```
+
+## User Prompt
+
+Explain"#
+    );
+  }
+
+  #[test]
+  fn selected_lines_are_numbered_right_aligned() {
+    let selection =
+      ContextFile { start_line: Some(8), end_line: Some(10), symbol: Some("parse".to_string()), ..file("src/lib.rs", "fn parse() {\n  todo!()\n}\n") };
+    let prompt = assemble_prompt(request(vec![selection]));
+
+    assert_eq!(
+      prompt,
+      r#"# Context
+
+Working directory: /repo
+
+## Files
+
+### src/lib.rs `parse` (lines 8-10)
+
+25 bytes, 3 lines
+```rust
+ 8 | fn parse() {
+ 9 |   todo!()
+10 | }
+```
+
+## User Prompt
+
+Explain"#
+    );
+  }
+
+  #[test]
+  fn truncated_and_dropped_files_say_so() {
+    let truncated = ContextFile { truncated_from: Some(1000), ..file("big.txt", "abc") };
+    let dropped = ContextFile { path: "dropped.rs".to_string(), ..Default::default() };
+    let prompt = assemble_prompt(request(vec![truncated, dropped]));
+
+    assert_eq!(
+      prompt,
+      r#"# Context
+
+Working directory: /repo
+
+## Files
+
+### big.txt
+
+3 of 1000 bytes, 1 line
+```
+abc
+```
+[... truncated, 3 of 1000 bytes shown ...]
+
+### dropped.rs
+
+Content not included.
+
+## User Prompt
+
+Explain"#
+    );
+  }
+
+  #[test]
+  fn variables_are_sorted() {
+    let variables = HashMap::from([("branch".to_string(), "main".to_string()), ("author".to_string(), "sam".to_string())]);
+    let mut request = request(Vec::new());
+    request.context.files = None;
+    request.context.variables = Some(variables);
+
+    assert_eq!(
+      assemble_prompt(request),
+      r#"# Context
+
+Working directory: /repo
+
+## Variables
+
+author: sam
+branch: main
+
+## User Prompt
+
+Explain"#
+    );
+  }
+}
@@ -0,0 +1,188 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+use dashmap::DashMap;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, oneshot, watch};
+
+use crate::types::{QueueState, SchedulerConfig};
+
+pub type QueueStateSender = Arc<watch::Sender<Option<QueueState>>>;
+
+#[derive(Debug)]
+pub struct Scheduler {
+  config: SchedulerConfig,
+  state: Mutex<SchedulerState>,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+  running: usize,
+  running_per_session: HashMap<String, usize>,
+  waiting: VecDeque<Waiter>,
+  next_ticket: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+  ticket: u64,
+  session_id: String,
+  queue: QueueStateSender,
+  grant: oneshot::Sender<SlotPermit>,
+}
+
+// A running slot; dropping it frees the slot for the next eligible waiter
+#[derive(Debug)]
+pub struct SlotPermit {
+  scheduler: Arc<Scheduler>,
+  session_id: String,
+}
+
+impl Scheduler {
+  pub fn new(config: SchedulerConfig) -> Self {
+    Self { config, state: Mutex::new(SchedulerState::default()) }
+  }
+
+  // Waits in FIFO order for a free slot, skipping past waiters whose session is already at its limit
+  pub async fn acquire(self: &Arc<Self>, session_id: &str, queue: QueueStateSender) -> SlotPermit {
+    let (grant, granted) = oneshot::channel();
+
+    let (ticket, rejected) = {
+      let mut state = self.state.lock().unwrap();
+      let ticket = state.next_ticket;
+      state.next_ticket += 1;
+      state.waiting.push_back(Waiter { ticket, session_id: session_id.to_string(), queue, grant });
+      (ticket, self.dispatch(&mut state))
+    };
+    drop(rejected);
+
+    // Leaves the queue if the caller gives up waiting (cancellation or timeout)
+    let _guard = WaitGuard { scheduler: self.clone(), ticket };
+
+    granted.await.expect("queued waiters are only removed by granting them a slot or by their own guard")
+  }
+
+  fn release(self: &Arc<Self>, session_id: &str) {
+    let rejected = {
+      let mut state = self.state.lock().unwrap();
+      state.running -= 1;
+
+      if let Some(running) = state.running_per_session.get_mut(session_id) {
+        *running -= 1;
+        if *running == 0 {
+          state.running_per_session.remove(session_id);
+        }
+      }
+
+      self.dispatch(&mut state)
+    };
+
+    // Permits whose waiter went away release again once the lock is no longer held
+    drop(rejected);
+  }
+
+  fn dispatch(self: &Arc<Self>, state: &mut SchedulerState) -> Vec<SlotPermit> {
+    let mut rejected = Vec::new();
+    let mut index = 0;
+
+    while state.running < self.config.max_concurrent && index < state.waiting.len() {
+      let session_running = state.running_per_session.get(&state.waiting[index].session_id).copied().unwrap_or_default();
+      if session_running >= self.config.max_per_session {
+        index += 1;
+        continue;
+      }
+
+      let waiter = state.waiting.remove(index).unwrap();
+      state.running += 1;
+      *state.running_per_session.entry(waiter.session_id.clone()).or_default() += 1;
+      waiter.queue.send_replace(None);
+
+      if let Err(permit) = waiter.grant.send(SlotPermit { scheduler: self.clone(), session_id: waiter.session_id }) {
+        rejected.push(permit);
+      }
+    }
+
+    update_positions(&state.waiting);
+
+    rejected
+  }
+}
+
+impl Drop for SlotPermit {
+  fn drop(&mut self) {
+    self.scheduler.release(&self.session_id);
+  }
+}
+
+struct WaitGuard {
+  scheduler: Arc<Scheduler>,
+  ticket: u64,
+}
+
+impl Drop for WaitGuard {
+  fn drop(&mut self) {
+    let mut state = self.scheduler.state.lock().unwrap();
+
+    if let Some(index) = state.waiting.iter().position(|waiter| waiter.ticket == self.ticket) {
+      let waiter = state.waiting.remove(index).unwrap();
+      waiter.queue.send_replace(None);
+      update_positions(&state.waiting);
+    }
+  }
+}
+
+fn update_positions(waiting: &VecDeque<Waiter>) {
+  for (index, waiter) in waiting.iter().enumerate() {
+    waiter.queue.send_replace(Some(QueueState::Slot { position: index + 1 }));
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct WorkdirLocks {
+  locks: DashMap<PathBuf, Arc<WorkdirLock>>,
+}
+
+#[derive(Debug, Default)]
+struct WorkdirLock {
+  mutex: Arc<AsyncMutex<()>>,
+  holder: Mutex<Option<String>>,
+}
+
+// Exclusive access to a working directory; released when dropped
+#[derive(Debug)]
+pub struct WorkdirGuard {
+  lock: Arc<WorkdirLock>,
+  _guard: OwnedMutexGuard<()>,
+}
+
+impl WorkdirLocks {
+  // Waits in FIFO order until no other writable job holds the (canonicalized) working directory
+  pub async fn lock(&self, working_dir: &str, job_id: &str, queue: &QueueStateSender) -> WorkdirGuard {
+    let path = tokio::fs::canonicalize(working_dir).await.unwrap_or_else(|_| PathBuf::from(working_dir));
+    let lock = self.locks.entry(path.clone()).or_default().clone();
+
+    let guard = match lock.mutex.clone().try_lock_owned() {
+      Ok(guard) => guard,
+      Err(_) => {
+        let holder = lock.holder.lock().unwrap().clone().unwrap_or_default();
+        queue.send_replace(Some(QueueState::WorkingDir { job_id: holder, working_dir: path.display().to_string() }));
+
+        let guard = lock.mutex.clone().lock_owned().await;
+        queue.send_replace(None);
+        guard
+      }
+    };
+
+    *lock.holder.lock().unwrap() = Some(job_id.to_string());
+
+    WorkdirGuard { lock, _guard: guard }
+  }
+}
+
+impl Drop for WorkdirGuard {
+  fn drop(&mut self) {
+    self.lock.holder.lock().unwrap().take();
+  }
+}
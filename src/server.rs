@@ -1,35 +1,49 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
 use rmcp::{
-  ErrorData as McpError, Peer, RoleServer, ServerHandler,
-  handler::server::{router::tool::ToolRouter, wrapper::Parameters},
-  model::{CallToolResult, Content, ErrorCode, LoggingLevel, LoggingMessageNotificationParam, ServerCapabilities, ServerInfo},
-  service::RequestContext,
+  ErrorData as McpError, Json, Peer, RoleServer, ServerHandler,
+  handler::server::{router::tool::ToolRouter, tool::cached_schema_for_type, wrapper::Parameters},
+  model::{
+    CallToolResult, Content, ErrorCode, LoggingLevel, LoggingMessageNotificationParam, ProgressNotificationParam, ProgressToken, ServerCapabilities, ServerInfo,
+  },
+  service::{NotificationContext, RequestContext},
   tool, tool_handler, tool_router,
 };
 use serde_json::Value;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, watch};
+use uuid::Uuid;
 
 use crate::{
-  codex::CodexClient,
-  types::{CodexConfig, CodexMessage, CodexPromptRequest, MessageType},
+  context::{self, ContextLoader},
+  jobs::{Job, JobManager},
+  store::PersistedJob,
+  types::{
+    CodexEvent, CodexPromptRequest, EventMsg, JobList, JobRequest, JobResultRequest, JobStatus, JobStatusReport, JobStatusRequest, JobSummary, PromptResult,
+    QueueState, ShutdownPhase,
+  },
 };
 
+// rmcp has no notification for a closed session, so the shutdown forwarder checks the transport this often
+const SESSION_CLOSE_POLL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct CodexMcp {
   tool_router: ToolRouter<Self>,
-  codex_client: CodexClient,
+  jobs: Arc<JobManager>,
+  context: Arc<ContextLoader>,
+  session_id: String,
 }
 
 #[tool_router]
 impl CodexMcp {
-  pub fn new() -> Self {
-    let codex_config = CodexConfig::from_env();
-
-    Self { tool_router: Self::tool_router(), codex_client: CodexClient::new(codex_config) }
+  pub fn new(jobs: Arc<JobManager>, context: Arc<ContextLoader>) -> Self {
+    // A new service instance is created for every MCP session
+    Self { tool_router: Self::tool_router(), jobs, context, session_id: Uuid::new_v4().to_string() }
   }
 
-  #[tool(description = "Execute a prompt using codex-cli")]
+  #[tool(description = "Execute a prompt using codex-cli", output_schema = cached_schema_for_type::<PromptResult>())]
   async fn prompt(&self, ctx: RequestContext<RoleServer>, Parameters(params): Parameters<CodexPromptRequest>) -> Result<CallToolResult, McpError> {
-    let (msg_tx, mut msg_rx) = mpsc::unbounded_channel();
+    let (job, events) = self.submit(params).await?;
 
     let _ = ctx
       .peer
@@ -40,70 +54,406 @@ impl CodexMcp {
       })
       .await;
 
-    self.codex_client.start_prompt_streaming(params, msg_tx).await.map_err(|e| McpError {
+    tokio::spawn(Self::forward_queue_state(ctx.peer.clone(), job.clone()));
+    let progress = ctx.meta.get_progress_token().map(ProgressReporter::new);
+    let forwarder = tokio::spawn(Self::forward_events(ctx.peer.clone(), events, progress));
+
+    let result = tokio::select! {
+      result = job.wait() => result,
+      _ = ctx.ct.cancelled() => {
+        job.cancel();
+        job.wait().await
+      }
+    };
+
+    // The event stream closes when the job finishes, so this only lets the last notifications go out before the result
+    let _ = forwarder.await;
+
+    Self::prompt_call_result(&job.id, result)
+  }
+
+  #[tool(description = "Start a prompt using codex-cli in the background and return its job id immediately")]
+  async fn prompt_async(&self, Parameters(params): Parameters<CodexPromptRequest>) -> Result<Json<JobSummary>, McpError> {
+    let (job, _) = self.submit(params).await?;
+
+    Ok(Json(job.summary()))
+  }
+
+  #[tool(description = "Report the status of a background job and the codex events it has buffered")]
+  async fn job_status(&self, Parameters(params): Parameters<JobStatusRequest>) -> Result<Json<JobStatusReport>, McpError> {
+    let since = params.since.unwrap_or_default();
+
+    let Some(job) = self.session_job(&params.job_id) else {
+      let persisted = self.find_persisted_job(&params.job_id, Some(since)).await?;
+      let next_cursor = since + persisted.events.len();
+
+      return Ok(Json(JobStatusReport { job: persisted.summary, events: persisted.events, next_cursor }));
+    };
+
+    let (events, next_cursor) = job.events_since(since);
+
+    Ok(Json(JobStatusReport {
+      job: job.summary(),
+      events: events.into_iter().map(|event| serde_json::to_value(event).unwrap_or_default()).collect(),
+      next_cursor,
+    }))
+  }
+
+  #[tool(description = "Return the result of a background job", output_schema = cached_schema_for_type::<PromptResult>())]
+  async fn job_result(&self, Parameters(params): Parameters<JobResultRequest>) -> Result<CallToolResult, McpError> {
+    let Some(job) = self.session_job(&params.job_id) else {
+      let persisted = self.find_persisted_job(&params.job_id, None).await?;
+      return Self::prompt_call_result(&persisted.summary.job_id, persisted.result);
+    };
+
+    let result = if params.wait.unwrap_or_default() { job.wait().await } else { job.result() };
+    if result.is_none() {
+      return Ok(CallToolResult::error(vec![Content::text(format!("Job {} is still running", job.id))]));
+    }
+
+    Self::prompt_call_result(&job.id, result)
+  }
+
+  #[tool(description = "Cancel a background job, terminating its codex process")]
+  async fn job_cancel(&self, Parameters(params): Parameters<JobRequest>) -> Result<Json<JobSummary>, McpError> {
+    let job = self.find_job(&params.job_id)?;
+    job.cancel();
+
+    Ok(Json(job.summary()))
+  }
+
+  #[tool(description = "List the background jobs started from this session")]
+  async fn job_list(&self) -> Result<Json<JobList>, McpError> {
+    Ok(Json(JobList { jobs: self.jobs.list(&self.session_id).iter().map(|job| job.summary()).collect() }))
+  }
+}
+
+impl CodexMcp {
+  async fn submit(&self, request: CodexPromptRequest) -> Result<(Arc<Job>, broadcast::Receiver<CodexEvent>), McpError> {
+    let backend = self.jobs.backends().get(request.backend.as_deref()).map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+
+    // The job keeps the request as the client sent it; the file contents loaded for it only go into the prompt
+    let mut params = request.clone();
+    let mut context_files = self.context.load(&mut params.context).await;
+    let mut invocation = backend.prepare(&params).map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+
+    // The budget is checked against the prompt the backend actually assembled
+    let budget = params.context_budget.or_else(|| self.jobs.context_window(&invocation.model).map(context::window_budget));
+    if let Some(budget) = budget
+      && context::pack(&mut params.context, &invocation.prompt, budget, &mut context_files)
+    {
+      invocation = backend.prepare(&params).map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+    }
+    self.jobs.submit(&self.session_id, backend, request, invocation, context_files).map_err(|e| McpError {
       code: ErrorCode::INTERNAL_ERROR,
       message: e.to_string().into(),
       data: None,
-    })?;
+    })
+  }
+
+  // Jobs started from other sessions are treated as unknown, so a job id alone doesn't give access to another client's work
+  fn session_job(&self, job_id: &str) -> Option<Arc<Job>> {
+    self.jobs.get(job_id).filter(|job| job.session_id == self.session_id)
+  }
 
-    let agent_message = self.get_agent_message(ctx, &mut msg_rx).await;
+  fn find_job(&self, job_id: &str) -> Result<Arc<Job>, McpError> {
+    self.session_job(job_id).ok_or_else(|| McpError::invalid_params(format!("Unknown job id: {}", job_id), None))
+  }
 
-    Ok(CallToolResult::success(vec![Content::text(agent_message)]))
+  async fn find_persisted_job(&self, job_id: &str, events_since: Option<usize>) -> Result<PersistedJob, McpError> {
+    self
+      .jobs
+      .load_persisted(job_id, events_since)
+      .await
+      .filter(|persisted| persisted.session_id == self.session_id)
+      .ok_or_else(|| McpError::invalid_params(format!("Unknown job id: {}", job_id), None))
   }
-}
 
-impl CodexMcp {
-  async fn get_agent_message(&self, ctx: RequestContext<RoleServer>, msg_rx: &mut mpsc::UnboundedReceiver<CodexMessage>) -> String {
+  fn prompt_call_result(job_id: &str, result: Option<PromptResult>) -> Result<CallToolResult, McpError> {
+    let result =
+      result.ok_or_else(|| McpError { code: ErrorCode::INTERNAL_ERROR, message: format!("Job {} finished without a result", job_id).into(), data: None })?;
+
+    let structured_content =
+      serde_json::to_value(&result).map_err(|e| McpError { code: ErrorCode::INTERNAL_ERROR, message: e.to_string().into(), data: None })?;
+
+    Ok(CallToolResult { content: Self::result_contents(&result), structured_content: Some(structured_content), is_error: Some(result.is_error), meta: None })
+  }
+
+  fn result_contents(result: &PromptResult) -> Vec<Content> {
+    let mut contents = Vec::new();
+
+    if let Some(failure) = &result.failure {
+      contents.push(Content::text(failure.to_string()));
+    }
+
+    let Some((final_message, agent_messages)) = result.agent_messages.split_last() else {
+      return contents;
+    };
+
+    contents.push(Content::text(final_message));
+
+    if !agent_messages.is_empty() {
+      let intermediate = agent_messages.iter().enumerate().map(|(i, message)| format!("{}. {}", i + 1, message)).collect::<Vec<_>>().join("\n\n");
+      contents.push(Content::text(format!("## Intermediate agent messages\n\n{}", intermediate)));
+    }
+
+    if !result.errors.is_empty() {
+      contents.push(Content::text(format!("## Errors\n\n{}", result.errors.join("\n"))));
+    }
+
+    if !result.stderr.is_empty() {
+      contents.push(Content::text(format!("## Stderr\n\n{}", result.stderr.join("\n"))));
+    }
+
+    if let Some(total) = &result.token_usage {
+      contents.push(Content::text(format!("Tokens used: {} (input: {}, output: {})", total.total_tokens, total.input_tokens, total.output_tokens)));
+    }
+
+    if !result.context_files.is_empty() {
+      let notes = result.context_files.iter().map(|note| format!("- {}", note)).collect::<Vec<_>>().join("\n");
+      contents.push(Content::text(format!("## Context files not included in full\n\n{}", notes)));
+    }
+
+    if let Some(session_id) = &result.session_id {
+      contents.push(Content::text(format!("Session id: {} (pass it as `session_id` to continue this conversation)", session_id)));
+    }
+
+    contents
+  }
+
+  async fn forward_queue_state(peer: Peer<RoleServer>, job: Arc<Job>) {
+    let mut queue = job.subscribe_queue();
+    let mut status = job.subscribe_status();
+
+    loop {
+      let message = match queue.borrow_and_update().clone() {
+        Some(QueueState::Slot { position }) => Some(format!("Queued behind other codex runs, position {} in the queue", position)),
+        Some(QueueState::WorkingDir { job_id, working_dir }) => Some(format!("Queued behind job {}, which is writing to {}", job_id, working_dir)),
+        None => None,
+      };
+
+      if let Some(message) = message {
+        Self::send_logging_message(peer.clone(), LoggingLevel::Info, Some("codex.queue".to_string()), message.into()).await;
+      }
+
+      if *status.borrow_and_update() != JobStatus::Queued {
+        break;
+      }
+
+      tokio::select! {
+        changed = queue.changed() => if changed.is_err() { break },
+        changed = status.changed() => if changed.is_err() { break },
+      }
+    }
+  }
+
+  async fn forward_shutdown(peer: Peer<RoleServer>, mut shutdown: watch::Receiver<ShutdownPhase>) {
     loop {
-      match msg_rx.recv().await {
-        Some(CodexMessage { msg: Some(msg_content), .. }) => {
-          tracing::info!("Message content: {msg_content:#?}");
-
-          match msg_content.msg_type {
-            MessageType::AgentMessage => {
-              return msg_content.message.unwrap_or_default();
-            }
-            MessageType::AgentReasoning => self.handle_angent_reasoning(ctx.peer.clone(), msg_content.text.unwrap_or_default().into()).await,
-            MessageType::TokenCount => self.handle_token_count(ctx.peer.clone(), serde_json::to_value(msg_content.info).unwrap_or_default()).await,
-            MessageType::Error => self.handle_error(ctx.peer.clone(), msg_content.message.unwrap_or_default().into()).await,
-            _ => {}
-          }
+      let phase = *shutdown.borrow_and_update();
+      let message = match phase {
+        ShutdownPhase::Serving => None,
+        ShutdownPhase::Draining { jobs, drain_timeout_ms } => {
+          Some(format!("Server is shutting down, waiting up to {} ms for {} unfinished codex jobs", drain_timeout_ms, jobs))
+        }
+        ShutdownPhase::Finished(report) => Some(format!("Server shutdown complete: {}", report)),
+      };
+
+      if let Some(message) = message {
+        Self::send_logging_message(peer.clone(), LoggingLevel::Warning, Some("codecho.shutdown".to_string()), message.into()).await;
+      }
+
+      if matches!(phase, ShutdownPhase::Finished(_)) {
+        break;
+      }
+
+      // Stop once the session is gone so every client that ever connected doesn't keep a task and its peer alive
+      let mut poll = tokio::time::interval(SESSION_CLOSE_POLL);
+      loop {
+        tokio::select! {
+          changed = shutdown.changed() => if changed.is_err() { return } else { break },
+          _ = poll.tick() => if peer.is_transport_closed() { return },
+        }
+      }
+    }
+  }
+
+  async fn forward_events(peer: Peer<RoleServer>, mut events: broadcast::Receiver<CodexEvent>, mut progress: Option<ProgressReporter>) {
+    // Command end events only carry the call id, so remember what each call ran
+    let mut commands = HashMap::new();
+
+    loop {
+      let msg_content = match events.recv().await {
+        Ok(CodexEvent::Message(message)) => match message.msg {
+          Some(msg_content) => msg_content,
+          None => continue,
+        },
+        Ok(CodexEvent::Stderr(line)) => {
+          Self::handle_stderr(peer.clone(), line.into()).await;
+          continue;
+        }
+        Ok(CodexEvent::Exited(_)) => continue,
+        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+          tracing::warn!("Dropped {} codex events while forwarding notifications", skipped);
+          continue;
+        }
+        Err(broadcast::error::RecvError::Closed) => break,
+      };
+
+      match msg_content {
+        EventMsg::AgentMessage { message } => ProgressReporter::report(&mut progress, &peer, format!("Agent: {}", first_line(&message))).await,
+        EventMsg::AgentReasoning { text } => {
+          ProgressReporter::report(&mut progress, &peer, format!("Thinking: {}", first_line(&text))).await;
+          Self::handle_angent_reasoning(peer.clone(), text.into()).await
+        }
+        EventMsg::TokenCount { info } => Self::handle_token_count(peer.clone(), serde_json::to_value(info).unwrap_or_default()).await,
+        EventMsg::Error { message } => Self::handle_error(peer.clone(), message.into()).await,
+        EventMsg::AgentMessageDelta { delta } | EventMsg::AgentReasoningDelta { delta } => Self::handle_delta(peer.clone(), delta.into()).await,
+        EventMsg::ExecCommandBegin { call_id, command, cwd } => {
+          let command = command_line(&command);
+          let data = match cwd {
+            Some(cwd) => format!("$ {} (in {})", command, cwd),
+            None => format!("$ {}", command),
+          };
+
+          commands.insert(call_id, command);
+          ProgressReporter::report(&mut progress, &peer, data.clone()).await;
+          Self::handle_exec(peer.clone(), LoggingLevel::Info, data.into()).await;
+        }
+        EventMsg::ExecCommandEnd { call_id, exit_code, .. } => {
+          let command = commands.remove(&call_id).unwrap_or(call_id);
+          let level = if exit_code == 0 { LoggingLevel::Info } else { LoggingLevel::Warning };
+          let data = format!("`{}` exited with status {}", command, exit_code);
+          ProgressReporter::report(&mut progress, &peer, data.clone()).await;
+          Self::handle_exec(peer.clone(), level, data.into()).await;
+        }
+        EventMsg::PatchApplyBegin { changes, .. } => {
+          let files = changes.keys().map(String::as_str).collect::<Vec<_>>().join(", ");
+          let data = format!("Applying patch to {}", files);
+          ProgressReporter::report(&mut progress, &peer, data.clone()).await;
+          Self::handle_patch(peer.clone(), LoggingLevel::Info, data.into()).await;
         }
-        Some(CodexMessage { msg: None, .. }) => {
-          // Skip messages without content
+        EventMsg::PatchApplyEnd { success, stderr, .. } => {
+          let (level, data) =
+            if success { (LoggingLevel::Info, "Patch applied".to_string()) } else { (LoggingLevel::Warning, format!("Patch failed: {}", stderr)) };
+          ProgressReporter::report(&mut progress, &peer, data.clone()).await;
+          Self::handle_patch(peer.clone(), level, data.into()).await;
         }
-        None => {
-          // Channel closed, return empty string
-          return String::new();
+        EventMsg::McpToolCallBegin { invocation, .. } => {
+          let data = format!("Calling MCP tool {}.{}", invocation.server, invocation.tool);
+          ProgressReporter::report(&mut progress, &peer, data.clone()).await;
+          Self::handle_mcp_tool_call(peer.clone(), data.into()).await;
         }
+        EventMsg::McpToolCallEnd { invocation, .. } => {
+          let data = format!("MCP tool {}.{} finished", invocation.server, invocation.tool);
+          ProgressReporter::report(&mut progress, &peer, data.clone()).await;
+          Self::handle_mcp_tool_call(peer.clone(), data.into()).await;
+        }
+        EventMsg::StreamError { message } => {
+          Self::send_logging_message(peer.clone(), LoggingLevel::Warning, Some("codex.stream".to_string()), message.into()).await
+        }
+        EventMsg::BackgroundEvent { message } => {
+          Self::send_logging_message(peer.clone(), LoggingLevel::Info, Some("codex.background".to_string()), message.into()).await
+        }
+        EventMsg::Unknown(event) => Self::send_logging_message(peer.clone(), LoggingLevel::Debug, Some("codex.event".to_string()), event).await,
+        _ => {}
       }
     }
   }
 
-  async fn handle_angent_reasoning(&self, peer: Peer<RoleServer>, data: Value) {
-    self.send_logging_message(peer, LoggingLevel::Info, Some("codex.reasoning".to_string()), data).await;
+  async fn handle_delta(peer: Peer<RoleServer>, data: Value) {
+    Self::send_logging_message(peer, LoggingLevel::Debug, Some("codex.delta".to_string()), data).await;
+  }
+
+  async fn handle_exec(peer: Peer<RoleServer>, level: LoggingLevel, data: Value) {
+    Self::send_logging_message(peer, level, Some("codex.exec".to_string()), data).await;
+  }
+
+  async fn handle_patch(peer: Peer<RoleServer>, level: LoggingLevel, data: Value) {
+    Self::send_logging_message(peer, level, Some("codex.patch".to_string()), data).await;
+  }
+
+  async fn handle_mcp_tool_call(peer: Peer<RoleServer>, data: Value) {
+    Self::send_logging_message(peer, LoggingLevel::Info, Some("codex.mcp".to_string()), data).await;
+  }
+
+  async fn handle_angent_reasoning(peer: Peer<RoleServer>, data: Value) {
+    Self::send_logging_message(peer, LoggingLevel::Info, Some("codex.reasoning".to_string()), data).await;
+  }
+
+  async fn handle_error(peer: Peer<RoleServer>, data: Value) {
+    Self::send_logging_message(peer, LoggingLevel::Error, None, data).await;
   }
 
-  async fn handle_error(&self, peer: Peer<RoleServer>, data: Value) {
-    self.send_logging_message(peer, LoggingLevel::Error, None, data).await;
+  async fn handle_stderr(peer: Peer<RoleServer>, data: Value) {
+    Self::send_logging_message(peer, LoggingLevel::Warning, Some("codex.stderr".to_string()), data).await;
   }
 
-  async fn handle_token_count(&self, peer: Peer<RoleServer>, data: Value) {
-    self.send_logging_message(peer, LoggingLevel::Debug, None, data).await;
+  async fn handle_token_count(peer: Peer<RoleServer>, data: Value) {
+    Self::send_logging_message(peer, LoggingLevel::Debug, None, data).await;
   }
 
-  async fn send_logging_message(&self, peer: Peer<RoleServer>, level: LoggingLevel, logger: Option<String>, data: Value) {
+  async fn send_logging_message(peer: Peer<RoleServer>, level: LoggingLevel, logger: Option<String>, data: Value) {
     let _ = peer.notify_logging_message(LoggingMessageNotificationParam { level, logger, data }).await;
   }
 }
 
+// Numbers the progress notifications of one `prompt` call; MCP requires progress to increase with every notification
+#[derive(Debug)]
+struct ProgressReporter {
+  token: ProgressToken,
+  count: u32,
+}
+
+impl ProgressReporter {
+  fn new(token: ProgressToken) -> Self {
+    Self { token, count: 0 }
+  }
+
+  // Only reports when the client asked for progress by sending a progress token
+  async fn report(progress: &mut Option<Self>, peer: &Peer<RoleServer>, message: String) {
+    let Some(progress) = progress else {
+      return;
+    };
+
+    progress.count += 1;
+    let _ = peer
+      .notify_progress(ProgressNotificationParam {
+        progress_token: progress.token.clone(),
+        progress: progress.count.into(),
+        total: None,
+        message: Some(message),
+      })
+      .await;
+  }
+}
+
+fn first_line(text: &str) -> &str {
+  text.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default()
+}
+
+// Codex runs shell commands as `bash -lc <script>`; the script alone is what is worth showing
+fn command_line(command: &[String]) -> String {
+  match command {
+    [_, flag, script] if flag == "-lc" || flag == "-c" => script.clone(),
+    _ => command.join(" "),
+  }
+}
+
 #[tool_handler]
 impl ServerHandler for CodexMcp {
   fn get_info(&self) -> ServerInfo {
     ServerInfo {
-      instructions: Some("A codex-cli wrapper to enable AI agent execution".into()),
+      instructions: Some(format!(
+        "A codex-cli wrapper to enable AI agent execution. Backends (select with the prompt's `backend` field): {}",
+        self.jobs.backends().describe()
+      )),
       capabilities: ServerCapabilities::builder().enable_tools().build(),
       ..Default::default()
     }
   }
+
+  async fn on_initialized(&self, context: NotificationContext<RoleServer>) {
+    tracing::info!("client initialized");
+    tokio::spawn(Self::forward_shutdown(context.peer, self.jobs.subscribe_shutdown()));
+  }
 }
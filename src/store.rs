@@ -0,0 +1,369 @@
+use std::{
+  sync::{Arc, Mutex, mpsc},
+  thread,
+  time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::sync::oneshot;
+
+use crate::types::*;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS jobs (
+    id TEXT PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    status TEXT NOT NULL,
+    prompt_preview TEXT NOT NULL,
+    request TEXT NOT NULL,
+    args TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    result TEXT,
+    input_tokens INTEGER,
+    output_tokens INTEGER,
+    total_tokens INTEGER,
+    created_at TEXT NOT NULL,
+    finished_at TEXT
+  );
+
+  CREATE INDEX IF NOT EXISTS jobs_created_at ON jobs (created_at);
+
+  CREATE TABLE IF NOT EXISTS job_events (
+    job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (job_id, seq)
+  );
+";
+
+#[derive(Debug)]
+pub struct JobStore {
+  ops: mpsc::Sender<StoreOp>,
+  reader: Arc<Mutex<Connection>>,
+}
+
+#[derive(Debug)]
+enum StoreOp {
+  InsertJob { summary: JobSummary, session_id: String, request: String, args: String, prompt: String },
+  StartJob { job_id: String },
+  InsertEvent { job_id: String, seq: usize, event: String },
+  FinishJob { job_id: String, status: String, result: String, token_usage: Option<TokenUsage> },
+  Flush { done: oneshot::Sender<()> },
+}
+
+#[derive(Debug)]
+pub struct PersistedJob {
+  pub summary: JobSummary,
+  pub session_id: String,
+  pub events: Vec<serde_json::Value>,
+  pub result: Option<PromptResult>,
+}
+
+impl JobStore {
+  pub fn open(config: &StoreConfig) -> Result<Self> {
+    if let Some(parent) = config.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+      std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+
+    let writer = Self::connect(config)?;
+    writer.execute_batch(SCHEMA).context("Failed to create the job store schema")?;
+
+    // Anything still queued or running belonged to a previous server process that is gone now
+    let interrupted = Self::stop_interrupted(&writer)?;
+    if interrupted > 0 {
+      tracing::warn!("Marked {} interrupted jobs from a previous run as stopped by a server shutdown", interrupted);
+    }
+
+    let reader = Self::connect(config)?;
+    let (ops, ops_rx) = mpsc::channel();
+    let retention_days = config.retention_days;
+    let max_jobs = config.max_jobs;
+
+    thread::Builder::new().name("codecho-store".to_string()).spawn(move || Self::write_loop(writer, ops_rx, retention_days, max_jobs))?;
+
+    tracing::info!("Persisting jobs to {}", config.path.display());
+
+    Ok(Self { ops, reader: Arc::new(Mutex::new(reader)) })
+  }
+
+  pub fn insert_job(&self, job: &JobSummary, session_id: &str, request: &CodexPromptRequest, invocation: &CodexInvocation) {
+    self.send(StoreOp::InsertJob {
+      summary: job.clone(),
+      session_id: session_id.to_string(),
+      request: serde_json::to_string(request).unwrap_or_default(),
+      args: serde_json::to_string(&invocation.args).unwrap_or_default(),
+      prompt: invocation.prompt.clone(),
+    });
+  }
+
+  pub fn start_job(&self, job_id: &str) {
+    self.send(StoreOp::StartJob { job_id: job_id.to_string() });
+  }
+
+  pub fn insert_event(&self, job_id: &str, seq: usize, message: &CodexMessage) {
+    self.send(StoreOp::InsertEvent { job_id: job_id.to_string(), seq, event: serde_json::to_string(message).unwrap_or_default() });
+  }
+
+  pub fn finish_job(&self, job_id: &str, status: JobStatus, result: &PromptResult) {
+    self.send(StoreOp::FinishJob {
+      job_id: job_id.to_string(),
+      status: status_name(status),
+      result: serde_json::to_string(result).unwrap_or_default(),
+      token_usage: result.token_usage.clone(),
+    });
+  }
+
+  // Resolves once every write queued before it has been applied
+  pub async fn flush(&self) {
+    let (done, flushed) = oneshot::channel();
+    self.send(StoreOp::Flush { done });
+    let _ = flushed.await;
+  }
+
+  pub async fn load(&self, job_id: &str, events_since: Option<usize>) -> Result<Option<PersistedJob>> {
+    let reader = self.reader.clone();
+    let job_id = job_id.to_string();
+
+    tokio::task::spawn_blocking(move || Self::load_blocking(&reader.lock().unwrap(), &job_id, events_since)).await?
+  }
+
+  fn connect(config: &StoreConfig) -> Result<Connection> {
+    let connection = Connection::open(&config.path).with_context(|| format!("Failed to open job store at {}", config.path.display()))?;
+    connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000;")?;
+    Ok(connection)
+  }
+
+  fn send(&self, op: StoreOp) {
+    if self.ops.send(op).is_err() {
+      tracing::error!("Job store writer has stopped, dropping write");
+    }
+  }
+
+  fn write_loop(connection: Connection, ops: mpsc::Receiver<StoreOp>, retention_days: u32, max_jobs: u32) {
+    let mut last_prune = None::<Instant>;
+
+    loop {
+      if last_prune.is_none_or(|last_prune| last_prune.elapsed() >= PRUNE_INTERVAL) {
+        if let Err(e) = Self::prune(&connection, retention_days, max_jobs) {
+          tracing::error!("Failed to prune job store: {e:#}");
+        }
+        last_prune = Some(Instant::now());
+      }
+
+      let Ok(op) = ops.recv() else {
+        break;
+      };
+
+      if let Err(e) = Self::apply(&connection, op) {
+        tracing::error!("Failed to write to job store: {e:#}");
+      }
+    }
+  }
+
+  fn apply(connection: &Connection, op: StoreOp) -> Result<()> {
+    match op {
+      StoreOp::InsertJob { summary, session_id, request, args, prompt } => {
+        connection.execute(
+          "INSERT INTO jobs (id, session_id, status, prompt_preview, request, args, prompt, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+          params![summary.job_id, session_id, status_name(summary.status), summary.prompt, request, args, prompt, now()],
+        )?;
+      }
+      StoreOp::StartJob { job_id } => {
+        connection.execute("UPDATE jobs SET status = ?2 WHERE id = ?1", params![job_id, status_name(JobStatus::Running)])?;
+      }
+      StoreOp::InsertEvent { job_id, seq, event } => {
+        connection.execute("INSERT INTO job_events (job_id, seq, event, created_at) VALUES (?1, ?2, ?3, ?4)", params![job_id, seq, event, now()])?;
+      }
+      StoreOp::FinishJob { job_id, status, result, token_usage } => {
+        connection.execute(
+          "UPDATE jobs SET status = ?2, result = ?3, input_tokens = ?4, output_tokens = ?5, total_tokens = ?6, finished_at = ?7 WHERE id = ?1",
+          params![
+            job_id,
+            status,
+            result,
+            token_usage.as_ref().map(|usage| usage.input_tokens),
+            token_usage.as_ref().map(|usage| usage.output_tokens),
+            token_usage.as_ref().map(|usage| usage.total_tokens),
+            now()
+          ],
+        )?;
+      }
+      StoreOp::Flush { done } => {
+        let _ = done.send(());
+      }
+    }
+
+    Ok(())
+  }
+
+  // Records the result the server would have given unfinished jobs had it stopped them: a `server_shutdown` failure.
+  // It's only kept in the database; `job_result` doesn't show it, since the jobs belonged to the old server's sessions
+  fn stop_interrupted(connection: &Connection) -> Result<usize> {
+    let mut statement = connection.prepare("SELECT id, request, args FROM jobs WHERE finished_at IS NULL")?;
+    let jobs =
+      statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?.collect::<Result<Vec<_>, _>>()?;
+
+    for (job_id, request, args) in &jobs {
+      let request = serde_json::from_str::<CodexPromptRequest>(request).unwrap_or_default();
+      let args = serde_json::from_str::<Vec<String>>(args).unwrap_or_default();
+      let result = PromptResult {
+        final_message: String::new(),
+        agent_messages: Vec::new(),
+        token_usage: None,
+        errors: Vec::new(),
+        model: invocation_setting(&args, "model", &["--model", "-m"]).or_else(|| request.model.map(|model| model.to_string())).unwrap_or_default(),
+        sandbox_mode: invocation_setting(&args, "sandbox", &["--sandbox", "-s"])
+          .or_else(|| request.sandbox_mode.map(|sandbox_mode| sandbox_mode.to_string()))
+          .unwrap_or_default(),
+        session_id: request.session_id,
+        context_files: Vec::new(),
+        stderr: Vec::new(),
+        duration_ms: 0,
+        exit_code: None,
+        timed_out: false,
+        is_error: true,
+        failure: Some(RunFailure::ServerShutdown),
+      };
+
+      connection.execute(
+        "UPDATE jobs SET status = ?2, result = ?3, finished_at = ?4 WHERE id = ?1",
+        params![job_id, status_name(JobStatus::from_result(&result)), serde_json::to_string(&result)?, now()],
+      )?;
+    }
+
+    Ok(jobs.len())
+  }
+
+  fn prune(connection: &Connection, retention_days: u32, max_jobs: u32) -> Result<()> {
+    let cutoff = (Utc::now() - chrono::Duration::days(retention_days.into())).to_rfc3339_opts(SecondsFormat::Millis, true);
+
+    let expired = connection.execute("DELETE FROM jobs WHERE created_at < ?1", params![cutoff])?;
+    let overflow = connection.execute("DELETE FROM jobs WHERE id NOT IN (SELECT id FROM jobs ORDER BY created_at DESC LIMIT ?1)", params![max_jobs])?;
+
+    if expired + overflow > 0 {
+      tracing::info!("Pruned {} jobs from the job store", expired + overflow);
+    }
+
+    Ok(())
+  }
+
+  fn load_blocking(connection: &Connection, job_id: &str, events_since: Option<usize>) -> Result<Option<PersistedJob>> {
+    let job = connection
+      .query_row("SELECT session_id, status, prompt_preview, result, created_at, finished_at FROM jobs WHERE id = ?1", params![job_id], |row| {
+        Ok((
+          row.get::<_, String>(0)?,
+          row.get::<_, String>(1)?,
+          row.get::<_, String>(2)?,
+          row.get::<_, Option<String>>(3)?,
+          row.get::<_, String>(4)?,
+          row.get(5)?,
+        ))
+      })
+      .optional()?;
+
+    let Some((session_id, status, prompt, result, created_at, finished_at)) = job else {
+      return Ok(None);
+    };
+
+    let mut events = Vec::new();
+    if let Some(since) = events_since {
+      let mut statement = connection.prepare("SELECT event FROM job_events WHERE job_id = ?1 AND seq >= ?2 ORDER BY seq")?;
+      events = statement
+        .query_map(params![job_id, since], |row| row.get::<_, String>(0))?
+        .filter_map(|event| event.ok().and_then(|event| serde_json::from_str(&event).ok()))
+        .collect();
+    }
+
+    Ok(Some(PersistedJob {
+      summary: JobSummary {
+        job_id: job_id.to_string(),
+        status: serde_json::from_value(status.into()).unwrap_or(JobStatus::Failed),
+        queue: None,
+        prompt,
+        created_at,
+        finished_at,
+      },
+      session_id,
+      events,
+      result: result.and_then(|result| serde_json::from_str(&result).ok()),
+    }))
+  }
+}
+
+// Reads a setting back from the stored invocation args: the arguments object of a codex-mcp tool call
+// (`[tool, json]`), or a `--flag value` / `--flag=value` pair of `codex exec` and agent CLIs
+fn invocation_setting(args: &[String], key: &str, flags: &[&str]) -> Option<String> {
+  if let [_, arguments] = args
+    && let Ok(serde_json::Value::Object(arguments)) = serde_json::from_str::<serde_json::Value>(arguments)
+  {
+    return arguments.get(key).and_then(|value| value.as_str()).map(str::to_string);
+  }
+
+  args.iter().enumerate().find_map(|(index, arg)| {
+    flags.iter().find_map(|flag| {
+      if arg == flag { args.get(index + 1).cloned() } else { arg.strip_prefix(flag).and_then(|rest| rest.strip_prefix('=')).map(str::to_string) }
+    })
+  })
+}
+
+fn status_name(status: JobStatus) -> String {
+  serde_json::to_value(status).ok().and_then(|status| status.as_str().map(str::to_string)).unwrap_or_default()
+}
+
+fn now() -> String {
+  Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  fn insert(connection: &Connection, job_id: &str, request: serde_json::Value, args: &[&str]) {
+    connection
+      .execute(
+        "INSERT INTO jobs (id, session_id, status, prompt_preview, request, args, prompt, created_at) VALUES (?1, 's', 'running', '', ?2, ?3, '', ?4)",
+        params![job_id, request.to_string(), serde_json::to_string(args).unwrap(), now()],
+      )
+      .unwrap();
+  }
+
+  fn result(connection: &Connection, job_id: &str) -> PromptResult {
+    let result: String = connection.query_row("SELECT result FROM jobs WHERE id = ?1", [job_id], |row| row.get(0)).unwrap();
+    serde_json::from_str(&result).unwrap()
+  }
+
+  #[test]
+  fn interrupted_jobs_keep_the_model_and_sandbox_they_ran_with() {
+    let connection = Connection::open_in_memory().unwrap();
+    connection.execute_batch(SCHEMA).unwrap();
+
+    let request = json!({ "prompt": "hi", "context": { "working_dir": "/repo" } });
+    let tool_call = json!({ "model": "gpt-5", "sandbox": "workspace-write", "cwd": "/repo" }).to_string();
+    let reply = json!({ "prompt": "again", "model": "gpt-5", "session_id": "abc", "context": { "working_dir": "/repo" } });
+
+    insert(&connection, "exec", request.clone(), &["exec", "--json", "--sandbox", "danger-full-access", "--model", "gpt-5", "-"]);
+    insert(&connection, "mcp", request.clone(), &["codex", &tool_call]);
+    insert(&connection, "reply", reply, &["codex-reply", r#"{"conversationId":"abc"}"#]);
+    insert(&connection, "cli", request, &["agent", "--model=gpt-5-codex"]);
+
+    assert_eq!(JobStore::stop_interrupted(&connection).unwrap(), 4);
+
+    let settings = |job_id| {
+      let result = result(&connection, job_id);
+      assert!(matches!(result.failure, Some(RunFailure::ServerShutdown)));
+      (result.model, result.sandbox_mode)
+    };
+    assert_eq!(settings("exec"), ("gpt-5".to_string(), "danger-full-access".to_string()));
+    assert_eq!(settings("mcp"), ("gpt-5".to_string(), "workspace-write".to_string()));
+    // The reply's args don't name them, so they come from its request
+    assert_eq!(settings("reply"), ("gpt-5".to_string(), String::new()));
+    assert_eq!(settings("cli"), ("gpt-5-codex".to_string(), String::new()));
+  }
+}
@@ -1,14 +1,16 @@
 use std::{
-  collections::HashMap,
+  collections::{BTreeMap, HashMap},
   env,
   fmt::{self, Display},
+  path::PathBuf,
   str::FromStr,
 };
 
 use rmcp::schemars;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Debug, Default, Clone, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 pub enum ReasoningEffort {
   #[serde(rename = "low")]
   #[schemars(rename = "low")]
@@ -44,7 +46,7 @@ impl Display for ReasoningEffort {
   }
 }
 
-#[derive(Debug, Default, Clone, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 pub enum Model {
   #[default]
   #[serde(rename = "gpt-5-codex")]
@@ -75,7 +77,7 @@ impl FromStr for Model {
   }
 }
 
-#[derive(Debug, Default, Clone, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 pub enum SandboxMode {
   #[default]
   #[serde(rename = "read-only")]
@@ -99,6 +101,12 @@ impl Display for SandboxMode {
   }
 }
 
+impl SandboxMode {
+  pub fn is_writable(&self) -> bool {
+    !matches!(self, Self::ReadOnly)
+  }
+}
+
 impl FromStr for SandboxMode {
   type Err = anyhow::Error;
   fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -115,14 +123,24 @@ impl FromStr for SandboxMode {
 pub struct CodexConfig {
   pub binary: String,
   pub timeout_ms: u64,
+  pub idle_timeout_ms: u64,
   pub model: Option<Model>,
   pub sandbox_mode: Option<SandboxMode>,
   pub reasoning_effort: Option<ReasoningEffort>,
+  pub kill_grace_ms: u64,
 }
 
 impl Default for CodexConfig {
   fn default() -> Self {
-    Self { binary: "codex".to_string(), model: None, sandbox_mode: None, reasoning_effort: None, timeout_ms: 1800000 }
+    Self {
+      binary: "codex".to_string(),
+      model: None,
+      sandbox_mode: None,
+      reasoning_effort: None,
+      timeout_ms: 1800000,
+      idle_timeout_ms: 300000,
+      kill_grace_ms: 5000,
+    }
   }
 }
 
@@ -138,11 +156,191 @@ impl CodexConfig {
       sandbox_mode,
       reasoning_effort,
       timeout_ms: env::var("CODEX_TIMEOUT").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(1800000),
+      idle_timeout_ms: env::var("CODEX_IDLE_TIMEOUT").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(300000),
+      kill_grace_ms: env::var("CODEX_KILL_GRACE_MS").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(5000),
+    }
+  }
+
+  // Request settings win over the configured defaults; every backend resolves them the same way
+
+  pub fn resolve_timeout_ms(&self, request: &CodexPromptRequest) -> u64 {
+    request.timeout.unwrap_or(self.timeout_ms)
+  }
+
+  pub fn resolve_idle_timeout_ms(&self, request: &CodexPromptRequest) -> u64 {
+    request.idle_timeout.unwrap_or(self.idle_timeout_ms)
+  }
+
+  pub fn resolve_sandbox_mode(&self, request: &CodexPromptRequest) -> SandboxMode {
+    request.sandbox_mode.clone().or_else(|| self.sandbox_mode.clone()).unwrap_or_default()
+  }
+
+  pub fn resolve_model(&self, request: &CodexPromptRequest) -> Model {
+    request.model.clone().or_else(|| self.model.clone()).unwrap_or_default()
+  }
+
+  pub fn resolve_reasoning_effort(&self, request: &CodexPromptRequest) -> ReasoningEffort {
+    request.reasoning_effort.clone().or_else(|| self.reasoning_effort.clone()).unwrap_or_default()
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackendsConfig {
+  // Backend used when a request doesn't name one
+  pub default: String,
+  // Extra JSONL CLI agents by name, read from the file at `CODECHO_BACKENDS_FILE`
+  pub cli: BTreeMap<String, CliBackendConfig>,
+}
+
+impl Default for BackendsConfig {
+  fn default() -> Self {
+    Self { default: "codex".to_string(), cli: BTreeMap::new() }
+  }
+}
+
+impl BackendsConfig {
+  pub fn from_env() -> anyhow::Result<Self> {
+    use anyhow::Context;
+
+    let cli = match env::var("CODECHO_BACKENDS_FILE") {
+      Ok(path) => {
+        let file = std::fs::read_to_string(&path).with_context(|| format!("Failed to read backends file {}", path))?;
+        serde_json::from_str(&file).with_context(|| format!("Invalid backends file {}", path))?
+      }
+      Err(_) => BTreeMap::new(),
+    };
+
+    Ok(Self { default: env::var("CODECHO_BACKEND").unwrap_or_else(|_| "codex".to_string()), cli })
+  }
+}
+
+// A CLI agent that prints one JSON event per line
+#[derive(Debug, Clone, Deserialize)]
+pub struct CliBackendConfig {
+  // Program and arguments; `{prompt}`, `{working_dir}`, `{model}`, `{sandbox_mode}` and `{reasoning_effort}` are
+  // substituted, and the prompt is written to the agent's stdin when the template has no `{prompt}`
+  pub command: Vec<String>,
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  // Reported as the run's model instead of the codex model
+  pub model: Option<String>,
+  // JSON pointer to the field that tells the agent's events apart
+  #[serde(default = "default_type_pointer")]
+  pub type_pointer: String,
+  #[serde(default)]
+  pub events: Vec<EventMapping>,
+  // Used instead of `command` to continue a session, with `{session_id}` also substituted
+  pub resume_command: Option<Vec<String>>,
+}
+
+fn default_type_pointer() -> String {
+  "/type".to_string()
+}
+
+// Turns one kind of agent event into a codex event
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventMapping {
+  // Value of the agent's type field this mapping applies to
+  #[serde(rename = "match")]
+  pub matches: String,
+  // Codex event type to produce, e.g. `agent_message`
+  pub event: String,
+  // Codex event field (JSON pointer) -> agent event field (JSON pointer)
+  #[serde(default)]
+  pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SchedulerConfig {
+  pub max_concurrent: usize,
+  pub max_per_session: usize,
+}
+
+impl Default for SchedulerConfig {
+  fn default() -> Self {
+    Self { max_concurrent: 4, max_per_session: 2 }
+  }
+}
+
+impl SchedulerConfig {
+  pub fn from_env() -> Self {
+    Self {
+      max_concurrent: env::var("CODECHO_MAX_CONCURRENT").ok().and_then(|s| s.parse::<usize>().ok()).filter(|max| *max > 0).unwrap_or(4),
+      max_per_session: env::var("CODECHO_MAX_PER_SESSION").ok().and_then(|s| s.parse::<usize>().ok()).filter(|max| *max > 0).unwrap_or(2),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StoreConfig {
+  pub path: PathBuf,
+  pub retention_days: u32,
+  pub max_jobs: u32,
+}
+
+impl Default for StoreConfig {
+  fn default() -> Self {
+    Self { path: default_store_path(), retention_days: 30, max_jobs: 10000 }
+  }
+}
+
+impl StoreConfig {
+  pub fn from_env() -> Self {
+    Self {
+      path: env::var("CODECHO_DB_PATH").map(PathBuf::from).unwrap_or_else(|_| default_store_path()),
+      retention_days: env::var("CODECHO_DB_RETENTION_DAYS").ok().and_then(|s| s.parse::<u32>().ok()).unwrap_or(30),
+      max_jobs: env::var("CODECHO_DB_MAX_JOBS").ok().and_then(|s| s.parse::<u32>().ok()).unwrap_or(10000),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShutdownConfig {
+  pub drain_timeout_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+  fn default() -> Self {
+    Self { drain_timeout_ms: 60000 }
+  }
+}
+
+impl ShutdownConfig {
+  pub fn from_env() -> Self {
+    Self { drain_timeout_ms: env::var("CODECHO_DRAIN_TIMEOUT_MS").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(60000) }
+  }
+}
+
+// Limits for context files codecho reads from disk itself
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContextConfig {
+  pub max_file_bytes: u64,
+  pub max_total_bytes: u64,
+  // Most files all glob patterns of a prompt together expand to
+  pub max_glob_files: usize,
+}
+
+impl Default for ContextConfig {
+  fn default() -> Self {
+    Self { max_file_bytes: 100_000, max_total_bytes: 400_000, max_glob_files: 200 }
+  }
+}
+
+impl ContextConfig {
+  pub fn from_env() -> Self {
+    Self {
+      max_file_bytes: env::var("CODECHO_CONTEXT_MAX_FILE_BYTES").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(100_000),
+      max_total_bytes: env::var("CODECHO_CONTEXT_MAX_TOTAL_BYTES").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(400_000),
+      max_glob_files: env::var("CODECHO_CONTEXT_MAX_GLOB_FILES").ok().and_then(|s| s.parse::<usize>().ok()).unwrap_or(200),
     }
   }
 }
 
-#[derive(Debug, Default, Clone, Deserialize, schemars::JsonSchema)]
+fn default_store_path() -> PathBuf {
+  env::var("HOME").map(|home| PathBuf::from(home).join(".codecho")).unwrap_or_default().join("codecho.db")
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct CodexPromptRequest {
   #[schemars(description = "The prompt to execute")]
   pub prompt: String,
@@ -150,15 +348,25 @@ pub struct CodexPromptRequest {
   pub context: Context,
   #[schemars(description = "The timeout for the prompt")]
   pub timeout: Option<u64>,
+  #[schemars(description = "Stop the run if codex emits no events for this many milliseconds (0 disables)")]
+  pub idle_timeout: Option<u64>,
   #[schemars(description = "The model to use")]
   pub model: Option<Model>,
   #[schemars(description = "The reasoning effort to use")]
   pub reasoning_effort: Option<ReasoningEffort>,
   #[schemars(description = "The sandbox mode to use")]
   pub sandbox_mode: Option<SandboxMode>,
+  #[schemars(description = "The agent backend to run the prompt with, as listed in the server instructions (defaults to the server's default backend)")]
+  pub backend: Option<String>,
+  #[schemars(description = "Continue the conversation of an earlier run (its result's session_id) instead of starting a new one; use the same backend")]
+  pub session_id: Option<String>,
+  #[schemars(
+    description = "Most tokens the prompt with its context may take, estimated at four bytes per token; context files are shortened or left out to fit. Defaults to half the model's context window once a run has reported it"
+  )]
+  pub context_budget: Option<u64>,
 }
 
-#[derive(Debug, Default, Clone, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct Context {
   #[schemars(description = "The files to include in the context")]
   pub files: Option<Vec<ContextFile>>,
@@ -168,18 +376,56 @@ pub struct Context {
   pub working_dir: String,
 }
 
-#[derive(Debug, Clone, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct ContextFile {
-  #[schemars(description = "The path to the file")]
+  #[schemars(
+    description = "The path to the file, or a glob pattern such as `src/**/*.rs` expanded against the working directory; a pattern starting with `!` excludes files from the other patterns' matches"
+  )]
   pub path: String,
-  #[schemars(description = "The content of the file")]
+  #[schemars(description = "The content of the file; read from disk when omitted, with the path relative to the working directory")]
   pub content: Option<String>,
+  #[schemars(description = "Include the file only from this line on (1-based)")]
+  pub start_line: Option<usize>,
+  #[schemars(description = "Include the file only up to and including this line (1-based)")]
+  pub end_line: Option<usize>,
+  #[schemars(
+    description = "Include only the definition of this function, type, impl, class or similar, with the comments above it; `Type::method` looks for `method` inside `Type`. Takes precedence over start_line/end_line"
+  )]
+  pub symbol: Option<String>,
+  #[schemars(description = "Files with a higher priority keep their content first when the prompt exceeds its context budget (default 0)")]
+  pub priority: Option<i32>,
+  // Set by codecho: whether a glob pattern matched the file rather than the client listing it
+  #[serde(skip)]
+  pub matched: bool,
+  // Set by codecho: size in bytes of a file, or of its selected lines, whose content was cut short
+  #[serde(skip)]
+  pub truncated_from: Option<u64>,
+}
+
+// A context file codecho read from disk but couldn't include in full
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ContextFileNote {
+  Truncated { path: String, included_bytes: u64, file_bytes: u64 },
+  Skipped { path: String, reason: String },
+  // Left out to fit the prompt's context budget
+  Dropped { path: String, estimated_tokens: u64 },
+}
+
+impl Display for ContextFileNote {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Truncated { path, included_bytes, file_bytes } => write!(f, "{}: truncated to {} of {} bytes", path, included_bytes, file_bytes),
+      Self::Skipped { path, reason } => write!(f, "{}: skipped ({})", path, reason),
+      Self::Dropped { path, estimated_tokens } => write!(f, "{}: left out to fit the context budget (about {} tokens)", path, estimated_tokens),
+    }
+  }
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct CodexMessage {
   pub id: Option<String>,
-  pub msg: Option<MessageContent>,
+  pub msg: Option<EventMsg>,
   pub prompt: Option<String>,
   pub model: Option<String>,
   pub sandbox: Option<String>,
@@ -191,28 +437,106 @@ pub struct CodexMessage {
   pub reasoning_effort: Option<String>,
   #[serde(rename = "reasoning summaries")]
   pub reasoning_summaries: Option<String>,
+  #[serde(rename = "session id")]
+  pub session_id: Option<String>,
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize)]
-pub struct MessageContent {
-  #[serde(rename = "type")]
-  pub msg_type: MessageType,
-  pub message: Option<String>,
-  pub text: Option<String>,
-  pub info: Option<TokenUsageInfo>,
-  pub model_context_window: Option<u64>,
+// The `msg` payload of a codex event line. Event types codecho does not model, or that fail to parse,
+// are kept verbatim in `Unknown` so they still reach the job log and clients.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventMsg {
+  SessionConfigured {
+    session_id: String,
+    model: Option<String>,
+  },
+  TaskStarted {
+    model_context_window: Option<u64>,
+  },
+  TaskComplete {
+    last_agent_message: Option<String>,
+  },
+  TurnAborted {
+    reason: Option<String>,
+  },
+  AgentMessage {
+    message: String,
+  },
+  AgentMessageDelta {
+    delta: String,
+  },
+  AgentReasoning {
+    text: String,
+  },
+  AgentReasoningDelta {
+    delta: String,
+  },
+  AgentReasoningSectionBreak {},
+  TokenCount {
+    info: Option<TokenUsageInfo>,
+  },
+  ExecCommandBegin {
+    call_id: String,
+    command: Vec<String>,
+    cwd: Option<String>,
+  },
+  ExecCommandOutputDelta {
+    call_id: String,
+    stream: Option<String>,
+  },
+  ExecCommandEnd {
+    call_id: String,
+    exit_code: i32,
+    #[serde(default)]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+    aggregated_output: Option<String>,
+    duration: Option<Value>,
+  },
+  PatchApplyBegin {
+    call_id: String,
+    #[serde(default)]
+    auto_approved: bool,
+    #[serde(default)]
+    changes: BTreeMap<String, Value>,
+  },
+  PatchApplyEnd {
+    call_id: String,
+    #[serde(default)]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+    success: bool,
+  },
+  McpToolCallBegin {
+    call_id: String,
+    invocation: McpInvocation,
+  },
+  McpToolCallEnd {
+    call_id: String,
+    invocation: McpInvocation,
+    duration: Option<Value>,
+    result: Option<Value>,
+  },
+  StreamError {
+    message: String,
+  },
+  BackgroundEvent {
+    message: String,
+  },
+  Error {
+    message: String,
+  },
+  #[serde(untagged)]
+  Unknown(Value),
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
-pub enum MessageType {
-  TaskStarted,
-  AgentReasoning,
-  #[default]
-  AgentMessage,
-  TokenCount,
-  AgentReasoningSectionBreak,
-  Error,
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct McpInvocation {
+  pub server: String,
+  pub tool: String,
+  pub arguments: Option<Value>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -222,7 +546,7 @@ pub struct TokenUsageInfo {
   pub model_context_window: Option<u64>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct TokenUsage {
   pub input_tokens: u64,
   pub cached_input_tokens: Option<u64>,
@@ -230,3 +554,248 @@ pub struct TokenUsage {
   pub reasoning_output_tokens: Option<u64>,
   pub total_tokens: u64,
 }
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodexInvocation {
+  pub args: Vec<String>,
+  pub prompt: String,
+  // Whether the agent reads the prompt from stdin; a single argument can't hold more than 128 KiB on Linux
+  pub prompt_on_stdin: bool,
+  pub working_dir: String,
+  pub model: String,
+  pub sandbox_mode: SandboxMode,
+  pub timeout_ms: u64,
+  pub idle_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum CodexEvent {
+  Message(Box<CodexMessage>),
+  Stderr(String),
+  Exited(RunExit),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RunExit {
+  pub exit_code: Option<i32>,
+  pub signal: Option<i32>,
+  pub stop: Option<StopCause>,
+  pub stderr_tail: Vec<String>,
+  // Set when a long-lived codex server failed the run instead of a process exiting
+  pub call_error: Option<String>,
+}
+
+// Why codecho stopped the process; None in `RunExit` means codex exited on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopCause {
+  Timeout,
+  Idle,
+  Cancelled,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RunTranscript {
+  pub agent_messages: Vec<String>,
+  pub token_usage: Option<TokenUsageInfo>,
+  pub errors: Vec<String>,
+  pub model: Option<String>,
+  pub sandbox: Option<String>,
+  pub session_id: Option<String>,
+  pub model_context_window: Option<u64>,
+  pub exit: Option<RunExit>,
+}
+
+impl RunTranscript {
+  pub fn failure(&self, timeout_ms: u64, idle_timeout_ms: u64) -> Option<RunFailure> {
+    match &self.exit {
+      Some(RunExit { stop: Some(StopCause::Timeout), .. }) => Some(RunFailure::TimedOut { timeout_ms }),
+      Some(RunExit { stop: Some(StopCause::Idle), .. }) => Some(RunFailure::IdleTimeout { idle_timeout_ms }),
+      Some(RunExit { stop: Some(StopCause::Cancelled), .. }) => Some(RunFailure::Cancelled),
+      Some(RunExit { call_error: Some(message), .. }) => Some(RunFailure::CallFailed { message: message.clone() }),
+      Some(exit) if exit.exit_code != Some(0) => Some(RunFailure::Exited { exit_code: exit.exit_code, signal: exit.signal }),
+      _ if self.agent_messages.is_empty() => Some(RunFailure::NoAgentMessage),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RunFailure {
+  #[schemars(description = "The run was killed after exceeding its timeout")]
+  TimedOut { timeout_ms: u64 },
+  #[schemars(description = "The run was killed after codex emitted no events for idle_timeout_ms")]
+  IdleTimeout { idle_timeout_ms: u64 },
+  #[schemars(description = "The codex process exited unsuccessfully; exit_code is null and signal is set when it was killed by a signal")]
+  Exited { exit_code: Option<i32>, signal: Option<i32> },
+  #[schemars(description = "The codex process could not be started")]
+  SpawnFailed { message: String },
+  #[schemars(description = "The codex process exited successfully without producing an agent message")]
+  NoAgentMessage,
+  #[schemars(description = "The codex MCP server failed the tool call or went away during the run")]
+  CallFailed { message: String },
+  #[schemars(description = "The client cancelled the request")]
+  Cancelled,
+  #[schemars(description = "The server shut down before the run could finish")]
+  ServerShutdown,
+}
+
+impl Display for RunFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::TimedOut { timeout_ms } => write!(f, "Codex run timed out after {} ms", timeout_ms),
+      Self::IdleTimeout { idle_timeout_ms } => write!(f, "Codex produced no output for {} ms and was stopped", idle_timeout_ms),
+      Self::Exited { exit_code, signal } => match (exit_code, signal) {
+        (Some(exit_code), _) => write!(f, "Codex process exited with status {}", exit_code),
+        (None, Some(signal)) => write!(f, "Codex process was terminated by signal {}", signal),
+        (None, None) => write!(f, "Codex process was terminated by a signal"),
+      },
+      Self::SpawnFailed { message } => write!(f, "Failed to start codex: {}", message),
+      Self::NoAgentMessage => write!(f, "Codex finished without producing an agent message"),
+      Self::CallFailed { message } => write!(f, "Codex MCP server failed the run: {}", message),
+      Self::Cancelled => write!(f, "Prompt cancelled by client, codex process terminated"),
+      Self::ServerShutdown => write!(f, "Server shut down before the codex run finished, codex process terminated"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, schemars::JsonSchema)]
+pub struct PromptResult {
+  #[schemars(description = "The last agent message of the run")]
+  pub final_message: String,
+  #[schemars(description = "Every agent message of the run in order, including the final one")]
+  pub agent_messages: Vec<String>,
+  #[schemars(description = "Total token usage reported by codex")]
+  pub token_usage: Option<TokenUsage>,
+  #[schemars(description = "Error events reported by codex during the run")]
+  pub errors: Vec<String>,
+  #[schemars(description = "The model the run used")]
+  pub model: String,
+  #[schemars(description = "The sandbox mode the run used")]
+  pub sandbox_mode: String,
+  #[schemars(description = "Wall-clock duration of the run in milliseconds")]
+  pub duration_ms: u64,
+  #[schemars(description = "Exit code of the codex process, if it exited normally")]
+  pub exit_code: Option<i32>,
+  #[schemars(description = "Whether the run was killed because it exceeded its timeout")]
+  pub timed_out: bool,
+  #[schemars(description = "Whether the run failed")]
+  pub is_error: bool,
+  #[schemars(description = "Why the run failed, if it did")]
+  pub failure: Option<RunFailure>,
+  #[schemars(description = "The last lines codex wrote to stderr, included when the run failed")]
+  #[serde(default)]
+  pub stderr: Vec<String>,
+  #[schemars(description = "The agent's session id; pass it as session_id to continue this conversation")]
+  #[serde(default)]
+  pub session_id: Option<String>,
+  #[schemars(description = "Context files read from disk that were truncated or skipped")]
+  #[serde(default)]
+  pub context_files: Vec<ContextFileNote>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+  Queued,
+  Running,
+  Completed,
+  Failed,
+  Cancelled,
+}
+
+impl JobStatus {
+  pub fn from_result(result: &PromptResult) -> Self {
+    match &result.failure {
+      None => Self::Completed,
+      Some(RunFailure::Cancelled | RunFailure::ServerShutdown) => Self::Cancelled,
+      Some(_) => Self::Failed,
+    }
+  }
+
+  pub fn is_finished(&self) -> bool {
+    !matches!(self, Self::Queued | Self::Running)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, schemars::JsonSchema)]
+#[serde(tag = "waiting_for", rename_all = "snake_case")]
+pub enum QueueState {
+  #[schemars(description = "Waiting for a free slot; position is 1-based")]
+  Slot { position: usize },
+  #[schemars(description = "Waiting for another writable job in the same working directory to finish")]
+  WorkingDir { job_id: String, working_dir: String },
+}
+
+#[derive(Debug, Clone, Serialize, schemars::JsonSchema)]
+pub struct JobSummary {
+  #[schemars(description = "The job id to pass to the other job tools")]
+  pub job_id: String,
+  #[schemars(description = "The current status of the job")]
+  pub status: JobStatus,
+  #[schemars(description = "What the job is waiting for while it is queued")]
+  pub queue: Option<QueueState>,
+  #[schemars(description = "The beginning of the job's prompt")]
+  pub prompt: String,
+  #[schemars(description = "When the job was submitted (RFC 3339)")]
+  pub created_at: String,
+  #[schemars(description = "When the job finished (RFC 3339)")]
+  pub finished_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, schemars::JsonSchema)]
+pub struct JobStatusReport {
+  #[serde(flatten)]
+  pub job: JobSummary,
+  #[schemars(description = "Codex events buffered since the requested cursor")]
+  pub events: Vec<Value>,
+  #[schemars(description = "The cursor to pass as `since` to receive only newer events")]
+  pub next_cursor: usize,
+}
+
+#[derive(Debug, Clone, Serialize, schemars::JsonSchema)]
+pub struct JobList {
+  #[schemars(description = "The jobs submitted from this session, oldest first")]
+  pub jobs: Vec<JobSummary>,
+}
+
+#[derive(Debug, Clone, Deserialize, schemars::JsonSchema)]
+pub struct JobRequest {
+  #[schemars(description = "The job id returned by prompt_async")]
+  pub job_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, schemars::JsonSchema)]
+pub struct JobStatusRequest {
+  #[schemars(description = "The job id returned by prompt_async")]
+  pub job_id: String,
+  #[schemars(description = "Only return events after this cursor (the `next_cursor` of a previous call)")]
+  pub since: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, schemars::JsonSchema)]
+pub struct JobResultRequest {
+  #[schemars(description = "The job id returned by prompt_async")]
+  pub job_id: String,
+  #[schemars(description = "Wait for the job to finish instead of failing while it is still running")]
+  pub wait: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownPhase {
+  Serving,
+  Draining { jobs: usize, drain_timeout_ms: u64 },
+  Finished(ShutdownReport),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShutdownReport {
+  pub finished: usize,
+  pub stopped: usize,
+}
+
+impl Display for ShutdownReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} codex jobs finished during the drain, {} were stopped", self.finished, self.stopped)
+  }
+}
@@ -0,0 +1,143 @@
+// Runs prompts through the codex-mcp backend against the stub server
+
+use std::{sync::Arc, time::Duration};
+
+use codecho::{
+  backend::Backends,
+  capabilities::CodexInfo,
+  codex::CodexClient,
+  codex_mcp::CodexMcpBackend,
+  jobs::{Job, JobManager},
+  prompt::assemble_prompt,
+  scheduler::Scheduler,
+  types::*,
+};
+
+const STUB: &str = env!("CARGO_BIN_EXE_stub_codex_mcp");
+
+// The stub reads its settings from the environment it inherits when the backend starts it, so the cases run one after
+// another in a single test, each with its own backend and therefore its own stub server
+#[tokio::test]
+async fn codex_mcp_backend_runs_prompts_on_the_stub_server() {
+  succeeds().await;
+  fails_the_call().await;
+  cancels_the_call().await;
+  continues_a_conversation().await;
+}
+
+async fn succeeds() {
+  let jobs = manager(&[]).await;
+  let result = run(&jobs, "hello").await;
+  let prompt = assemble_prompt(request("hello"));
+
+  assert!(result.failure.is_none(), "{:?}", result.failure);
+  assert_eq!(result.final_message, format!("Received {} characters of prompt", prompt.len()));
+  assert_eq!(result.session_id.as_deref(), Some("stub-session"));
+
+  let usage = result.token_usage.expect("token usage");
+  assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (120, 30, 150));
+  assert_eq!(jobs.context_window(&result.model), Some(272000));
+
+  jobs.shutdown(Duration::ZERO).await;
+}
+
+async fn fails_the_call() {
+  let jobs = manager(&[("STUB_FAIL", "1")]).await;
+  let result = run(&jobs, "hello").await;
+
+  assert!(result.is_error);
+  assert!(matches!(&result.failure, Some(RunFailure::CallFailed { message }) if message == "stub failure"), "{:?}", result.failure);
+
+  jobs.shutdown(Duration::ZERO).await;
+}
+
+async fn cancels_the_call() {
+  let jobs = manager(&[("STUB_EVENT_DELAY_MS", "200")]).await;
+  let job = submit(&jobs, "hello");
+
+  let mut status = job.subscribe_status();
+  status.wait_for(|status| *status == JobStatus::Running).await.unwrap();
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  job.cancel();
+
+  let result = job.wait().await.expect("result");
+  assert!(matches!(result.failure, Some(RunFailure::Cancelled)), "{:?}", result.failure);
+  assert!(result.final_message.is_empty());
+
+  // The stub reported the aborted turn, so its server is kept for the next prompt
+  let result = run(&jobs, "again").await;
+  assert!(result.failure.is_none(), "{:?}", result.failure);
+
+  jobs.shutdown(Duration::ZERO).await;
+}
+
+async fn continues_a_conversation() {
+  let jobs = manager(&[]).await;
+  let backend = jobs.backends().get(None).unwrap();
+
+  let reply = CodexPromptRequest { session_id: Some("stub-session".to_string()), ..request("again") };
+  assert!(backend.prepare(&reply).is_err(), "unknown sessions can't be continued");
+
+  let start = CodexPromptRequest { sandbox_mode: Some(SandboxMode::WorkspaceWrite), ..request("hello") };
+  let invocation = backend.prepare(&start).unwrap();
+  let (job, _events) = jobs.submit("test", backend.clone(), start, invocation, Vec::new()).unwrap();
+  job.wait().await.expect("result");
+
+  // The reply runs with the conversation's sandbox, not the read-only default of its own request
+  let invocation = backend.prepare(&reply).unwrap();
+  assert_eq!(invocation.args[0], "codex-reply");
+  assert_eq!(invocation.sandbox_mode.to_string(), SandboxMode::WorkspaceWrite.to_string());
+
+  let (job, _events) = jobs.submit("test", backend.clone(), reply, invocation, Vec::new()).unwrap();
+  let result = job.wait().await.expect("result");
+  assert!(result.failure.is_none(), "{:?}", result.failure);
+  assert_eq!(result.sandbox_mode, SandboxMode::WorkspaceWrite.to_string());
+
+  let switch = CodexPromptRequest { session_id: Some("stub-session".to_string()), sandbox_mode: Some(SandboxMode::ReadOnly), ..request("again") };
+  assert!(backend.prepare(&switch).is_err(), "a continued conversation keeps its sandbox");
+
+  jobs.shutdown(Duration::ZERO).await;
+}
+
+async fn manager(env: &[(&str, &str)]) -> Arc<JobManager> {
+  // SAFETY: the cases run one at a time and nothing else in this test binary reads the environment concurrently
+  unsafe {
+    std::env::remove_var("STUB_FAIL");
+    std::env::remove_var("STUB_EVENT_DELAY_MS");
+    for (name, value) in env {
+      std::env::set_var(name, value);
+    }
+  }
+
+  let config = CodexConfig { binary: STUB.to_string(), kill_grace_ms: 2000, ..Default::default() };
+  let info = CodexInfo::detect(STUB).await.expect("stub version");
+  let backends = Backends::new(
+    BackendsConfig { default: "codex-mcp".to_string(), ..Default::default() },
+    CodexClient::new(config.clone(), info.clone()),
+    CodexMcpBackend::new(config.clone(), info),
+    &config,
+  )
+  .unwrap();
+
+  Arc::new(JobManager::new(backends, Scheduler::new(SchedulerConfig::default()), None))
+}
+
+fn request(prompt: &str) -> CodexPromptRequest {
+  CodexPromptRequest {
+    prompt: prompt.to_string(),
+    context: Context { working_dir: env!("CARGO_MANIFEST_DIR").to_string(), ..Default::default() },
+    ..Default::default()
+  }
+}
+
+fn submit(jobs: &Arc<JobManager>, prompt: &str) -> Arc<Job> {
+  let request = request(prompt);
+  let backend = jobs.backends().get(None).unwrap();
+  let invocation = backend.prepare(&request).unwrap();
+  let (job, _events) = jobs.submit("test", backend, request, invocation, Vec::new()).unwrap();
+  job
+}
+
+async fn run(jobs: &Arc<JobManager>, prompt: &str) -> PromptResult {
+  submit(jobs, prompt).wait().await.expect("result")
+}